@@ -0,0 +1 @@
+ALTER TABLE words ADD COLUMN anki_note_id BIGINT;
@@ -0,0 +1,10 @@
+CREATE TABLE execution(
+    last_update TEXT NOT NULL PRIMARY KEY
+);
+
+INSERT INTO execution(last_update)
+SELECT to_char(MAX(last_update), 'YYYY-MM-DD"T"HH24:MI:SS"+00:00"')
+FROM sync_cursor
+HAVING COUNT(*) > 0;
+
+DROP TABLE sync_cursor;
@@ -0,0 +1,17 @@
+CREATE TABLE sync_cursor(
+    login TEXT NOT NULL,
+    student_id BIGINT NOT NULL,
+    target TEXT NOT NULL,
+    last_update TIMESTAMP NOT NULL,
+
+    PRIMARY KEY(login, student_id, target)
+);
+
+-- The single global row does not know whom it belongs to, so it becomes the legacy cursor
+-- with an empty key, which is used by every student until it has a cursor of its own.
+INSERT INTO sync_cursor(login, student_id, target, last_update)
+SELECT '', 0, '', MAX(last_update::TIMESTAMPTZ AT TIME ZONE 'UTC')
+FROM execution
+HAVING COUNT(*) > 0;
+
+DROP TABLE execution;
@@ -0,0 +1,6 @@
+ALTER TABLE words RENAME COLUMN exported_at TO expires_at;
+
+ALTER TABLE words
+    ALTER COLUMN student_id TYPE INTEGER,
+    ALTER COLUMN wordset_id TYPE INTEGER,
+    ALTER COLUMN word_id TYPE INTEGER;
@@ -0,0 +1,17 @@
+-- The initial migration created `words` with INTEGER ids and `expires_at`,
+-- while the application always used BIGINT ids and `exported_at`.
+ALTER TABLE words
+    ALTER COLUMN student_id TYPE BIGINT,
+    ALTER COLUMN wordset_id TYPE BIGINT,
+    ALTER COLUMN word_id TYPE BIGINT;
+
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_schema = current_schema() AND table_name = 'words' AND column_name = 'expires_at'
+    ) THEN
+        ALTER TABLE words RENAME COLUMN expires_at TO exported_at;
+    END IF;
+END
+$$;
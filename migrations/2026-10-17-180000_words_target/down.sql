@@ -0,0 +1,9 @@
+-- Keeps a single row per word, preferring the one without a target
+DELETE FROM words a USING words b
+WHERE a.student_id = b.student_id
+  AND a.wordset_id = b.wordset_id
+  AND a.word_id = b.word_id
+  AND a.target > b.target;
+ALTER TABLE words DROP CONSTRAINT words_pkey;
+ALTER TABLE words DROP COLUMN target;
+ALTER TABLE words ADD PRIMARY KEY (student_id, wordset_id, word_id);
@@ -0,0 +1,5 @@
+-- Words are tracked per target, so a student could be synced to several decks or profiles.
+-- Words exported before keep the empty target and count as exported to every target.
+ALTER TABLE words ADD COLUMN target TEXT NOT NULL DEFAULT '';
+ALTER TABLE words DROP CONSTRAINT words_pkey;
+ALTER TABLE words ADD PRIMARY KEY (student_id, target, wordset_id, word_id);
@@ -0,0 +1,9 @@
+-- Counts of every finished sync, `status` prints the last one
+CREATE TABLE sync_run(
+    finished_at TIMESTAMP NOT NULL PRIMARY KEY,
+    added BIGINT NOT NULL,
+    updated BIGINT NOT NULL,
+    skipped BIGINT NOT NULL,
+    failed BIGINT NOT NULL,
+    error TEXT
+);
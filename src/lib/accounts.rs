@@ -0,0 +1,170 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use log::{error, info};
+use serde::Deserialize;
+use super::errors::Result;
+use super::repository::{get_token, save_token, Pool};
+use super::sink::SinkConfig;
+use super::skyeng::{Skyeng, SkyengUrls};
+
+/// Anki deck or other sinks receiving the words of a student
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Target {
+    /// AnkiConnect url, `anki.url` of the config if it is not set
+    #[serde(default)]
+    pub anki_url: String,
+    /// Anki profile loaded before the notes are added
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// deck of the notes, `anki.deck` of the config if it is not set
+    #[serde(default)]
+    pub deck: Option<String>,
+    /// where the notes go, `sinks` of the config or AnkiConnect if it is not set
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+}
+
+impl Target {
+    /// Name of the target in the sync cursor and the stored words,
+    /// `default_deck` is used if the target has no deck
+    pub fn name(&self, default_deck: &str) -> String {
+        let deck = self.deck.as_deref().unwrap_or(default_deck);
+        match &self.profile {
+            Some(profile) => format!("{}/{}", profile, deck),
+            None => deck.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Student {
+    pub id: u32,
+    /// the default target is used if none is listed
+    #[serde(default)]
+    pub targets: Vec<Target>,
+}
+
+/// Skyeng login with the students it has access to
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct Account {
+    pub username: String,
+    pub password: String,
+    pub students: Vec<Student>,
+}
+
+impl Account {
+    /// Skyeng client reusing the token stored for the login, refreshed tokens are stored back
+    pub fn client(&self, pool: &Pool, urls: &SkyengUrls) -> Result<Skyeng> {
+        let token = get_token(pool, &self.username)?;
+        let mut skyeng = Skyeng::new_with_token(token, self.username.clone(), self.password.clone())
+            .with_urls(urls.clone());
+
+        let callback_pool = pool.clone();
+        let login = self.username.clone();
+        skyeng.on_token_update(move |token| {
+            info!("Token updated for {}", login);
+            if let Err(e) = save_token(&callback_pool, &login, token) {
+                error!("Failed to save token to db: {}", e);
+            }
+        });
+        Ok(skyeng)
+    }
+}
+
+/// Skyeng clients of the accounts, kept between runs to reuse their cookies and tokens
+#[derive(Default)]
+pub struct SkyengClients {
+    clients: HashMap<String, Skyeng>,
+    urls: SkyengUrls,
+}
+
+impl SkyengClients {
+    pub fn new(urls: SkyengUrls) -> Self {
+        Self {
+            clients: HashMap::new(),
+            urls,
+        }
+    }
+
+    pub fn get(&mut self, pool: &Pool, account: &Account) -> Result<&mut Skyeng> {
+        match self.clients.entry(account.username.clone()) {
+            Entry::Occupied(client) => Ok(client.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(account.client(pool, &self.urls)?)),
+        }
+    }
+}
+
+/// Adds the default target to the students without targets,
+/// the default url and sinks to the targets without them
+pub(crate) fn with_defaults(accounts: &mut [Account], anki_url: Option<&String>, sinks: &[SinkConfig]) {
+    for student in accounts.iter_mut().flat_map(|a| a.students.iter_mut()) {
+        if student.targets.is_empty() {
+            student.targets.push(Target::default());
+        }
+        for target in student.targets.iter_mut().filter(|t| t.sinks.is_empty()) {
+            target.sinks = match sinks {
+                [] => vec![SinkConfig::Anki],
+                sinks => sinks.to_vec(),
+            };
+        }
+        if let Some(url) = anki_url {
+            for target in student.targets.iter_mut().filter(|t| t.anki_url.is_empty()) {
+                target.anki_url = url.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_accounts() {
+        let json = r#"[{
+            "username": "parent@mail.com",
+            "password": "secret",
+            "students": [
+                {"id": 1},
+                {"id": 2, "targets": [
+                    {"deck": "Kid"},
+                    {"anki_url": "http://tablet:8765", "profile": "Kid"},
+                    {"sinks": [{"kind": "json", "path": "kid.jsonl"}]}
+                ]}
+            ]
+        }]"#;
+        let mut accounts = serde_json::from_str::<Vec<Account>>(json).unwrap();
+        with_defaults(&mut accounts, Some(&"http://localhost:8765".to_string()), &[]);
+        let students = &accounts[0].students;
+        assert_eq!(students[0].targets, vec![Target {
+            anki_url: "http://localhost:8765".to_string(),
+            sinks: vec![SinkConfig::Anki],
+            ..Target::default()
+        }]);
+        assert_eq!(students[1].targets[2].sinks, vec![SinkConfig::Json { path: "kid.jsonl".into() }]);
+        assert_eq!(students[1].targets[0].deck, Some("Kid".to_string()));
+        assert_eq!(students[1].targets[0].anki_url, "http://localhost:8765");
+        assert_eq!(students[1].targets[1].anki_url, "http://tablet:8765");
+
+        let mut accounts = serde_json::from_str::<Vec<Account>>(json).unwrap();
+        let markdown = SinkConfig::Markdown { path: "words.md".into() };
+        with_defaults(&mut accounts, None, std::slice::from_ref(&markdown));
+        assert_eq!(accounts[0].students[0].targets[0].anki_url, "");
+        assert_eq!(accounts[0].students[0].targets[0].sinks, vec![markdown]);
+    }
+
+    #[test]
+    fn test_target_name() {
+        let target = Target {
+            profile: Some("Kid".to_string()),
+            ..Target::default()
+        };
+        assert_eq!(target.name("Default"), "Kid/Default");
+        assert_eq!(Target::default().name("Default"), "Default");
+        let target = Target {
+            deck: Some("Kid".to_string()),
+            ..Target::default()
+        };
+        assert_eq!(target.name("Default"), "Kid");
+    }
+}
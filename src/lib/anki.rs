@@ -1,15 +1,104 @@
+use std::collections::BTreeMap;
 use super::errors::{Error, Result};
-use serde::Serialize;
+use super::metrics::metrics;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+const API_VERSION: u8 = 6;
+/// Amount of actions sent in a single `multi` request
+const BATCH_SIZE: usize = 100;
 
 #[derive(Serialize, Debug)]
-struct Action {
-    pub version: String,
+struct Action<P> {
+    pub version: u8,
     pub action: String,
-    pub params: Option<Note>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params: Option<P>,
+}
+
+/// Every AnkiConnect call answers with HTTP 200 and reports failures
+/// (duplicate note, missing deck, unknown model) in this envelope.
+#[derive(Deserialize, Debug)]
+struct Response<R> {
+    pub result: Option<R>,
+    pub error: Option<String>,
+}
+
+impl<R> Response<R> {
+    fn into_result(self) -> Result<Option<R>> {
+        match self.error {
+            Some(message) => Err(Error::AnkiConnect { message }),
+            None => Ok(self.result),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct NoteParams {
+    pub note: Note,
+}
+
+#[derive(Serialize, Debug)]
+struct NoteUpdateParams {
+    pub note: NoteUpdate,
+}
+
+/// Attachments are linked into their fields again, as the fields are replaced
+#[derive(Serialize, Debug)]
+struct NoteUpdate {
+    pub id: u64,
+    pub fields: Fields,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub audio: Vec<Attachment>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub picture: Vec<Attachment>,
+}
+
+impl NoteUpdate {
+    fn new(id: u64, note: Note) -> Self {
+        Self {
+            id,
+            fields: note.fields,
+            audio: note.audio,
+            picture: note.picture,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct MediaFileParams<'a> {
+    pub filename: &'a str,
+    pub data: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ProfileParams<'a> {
+    pub name: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+struct DeckParams<'a> {
+    pub deck: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+struct QueryParams<'a> {
+    pub query: &'a str,
 }
 
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
+struct ModelParams<'a> {
+    pub model_name: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+struct MultiParams<P> {
+    pub actions: Vec<Action<P>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct Note {
     pub deck_name: String,
     pub model_name: String,
@@ -20,13 +109,10 @@ pub struct Note {
     pub picture: Vec<Attachment>,
 }
 
-#[derive(Serialize, Debug)]
-pub struct Fields {
-    #[serde(rename = "Text")]
-    pub text: String,
-}
+/// Field name -> field content of the note model
+pub type Fields = BTreeMap<String, String>;
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Options{
     pub allow_duplicate: bool, //false
@@ -35,7 +121,7 @@ pub struct Options{
 
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct DuplicateScopeOptions {
     pub deck_name: String, //"Default"
@@ -43,7 +129,7 @@ pub struct DuplicateScopeOptions {
     pub check_all_models: bool, //false
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct Attachment {
     pub url: String,
     pub filename: String,
@@ -63,42 +149,309 @@ impl Anki {
             url,
         }
     }
-    pub async fn sync(&self) -> Result<()> {
+
+    async fn invoke<P: Serialize, R: DeserializeOwned>(&self, action: &str, params: Option<P>) -> Result<Option<R>> {
         let data = Action {
-            version: "6".to_string(),
-            action: "sync".to_string(),
-            params: None,
+            version: API_VERSION,
+            action: action.to_string(),
+            params,
         };
         let path = self.url.clone();
-        self.client.post(&path)
+        let _timer = metrics().request("anki", action);
+        // separate statements, so no `Error` is held across an await and the future stays `Send`
+        let response = self.client.post(&path)
             .json(&data)
             .send().await
+            .map_err(|e| Error::Reqwest { e, path: path.clone() })?;
+        let body = response
+            .text().await
+            .map_err(|e| Error::Reqwest { e, path })?;
+        serde_json::from_str::<Response<R>>(&body)
+            .map_err(|e| Error::DeserializationError { e, message: body.clone() })?
+            .into_result()
+    }
+
+    /// Version of the AnkiConnect API, answers as soon as Anki is running
+    pub async fn version(&self) -> Result<u64> {
+        self.invoke::<(), u64>("version", None)
+            .await?
+            .ok_or(Error::AnkiConnect { message: "version returned no version".to_string() })
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        self.invoke::<(), serde_json::Value>("sync", None)
+            .await
             .map(|_| ())
-            .map_err(|e| Error::Reqwest { e, path })
     }
 
-    pub async fn add_note(&self, note: Note) -> Result<()> {
-        let data = Action {
-            version: "6".to_string(),
-            action: "addNote".to_string(),
-            params: Some(note),
-        };
-        let path = self.url.clone();
-        self.client.post(&path)
-            .json(&data)
-            .send().await
+    /// Adds a note and returns the id Anki assigned to it
+    pub async fn add_note(&self, note: Note) -> Result<u64> {
+        self.invoke("addNote", Some(NoteParams { note }))
+            .await?
+            .ok_or(Error::AnkiConnect { message: "addNote returned no note id".to_string() })
+    }
+
+    /// Adds notes in batches and returns a result per note in the order of `notes`.
+    ///
+    /// Batches are not sent with `addNotes`: older AnkiConnect reports a failed note only as `null`,
+    /// newer one fails the whole call while keeping the added notes, so their ids are lost.
+    /// Every note is sent as a separate `addNote` inside of `multi` instead,
+    /// which keeps the id or the error message of each note.
+    /// A single note needs no `multi` and is added right away.
+    pub async fn add_notes(&self, notes: Vec<Note>) -> Result<Vec<Result<u64>>> {
+        let mut notes = notes;
+        if notes.len() == 1 {
+            return Ok(vec![self.add_note(notes.remove(0)).await]);
+        }
+        let mut results = Vec::with_capacity(notes.len());
+        while !notes.is_empty() {
+            let rest = notes.split_off(BATCH_SIZE.min(notes.len()));
+            let batch = std::mem::replace(&mut notes, rest)
+                .into_iter()
+                .map(|note| NoteParams { note })
+                .collect();
+            let responses = self.multi::<NoteParams, u64>("addNote", batch).await?;
+            results.extend(responses.into_iter().map(|r| r.and_then(|id| {
+                id.ok_or(Error::AnkiConnect { message: "addNote returned no note id".to_string() })
+            })));
+        }
+        Ok(results)
+    }
+
+    /// Replaces the fields of an existing note with the ones of the note, including its attachments
+    pub async fn update_note_fields(&self, id: u64, note: Note) -> Result<()> {
+        self.invoke::<_, serde_json::Value>("updateNoteFields", Some(NoteUpdateParams {
+            note: NoteUpdate::new(id, note)
+        }))
+            .await
+            .map(|_| ())
+    }
+
+    /// Switches Anki to the profile, all the following actions are applied to its collection
+    pub async fn load_profile(&self, name: &str) -> Result<()> {
+        self.invoke::<_, serde_json::Value>("loadProfile", Some(ProfileParams { name }))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn deck_names(&self) -> Result<Vec<String>> {
+        self.invoke::<(), Vec<String>>("deckNames", None)
+            .await
+            .map(|names| names.unwrap_or_default())
+    }
+
+    /// Creates the deck with all of its parents and returns its id
+    pub async fn create_deck(&self, deck: &str) -> Result<u64> {
+        self.invoke("createDeck", Some(DeckParams { deck }))
+            .await?
+            .ok_or(Error::AnkiConnect { message: "createDeck returned no deck id".to_string() })
+    }
+
+    /// Ids of the notes matching the Anki search query
+    pub async fn find_notes(&self, query: &str) -> Result<Vec<u64>> {
+        self.invoke::<_, Vec<u64>>("findNotes", Some(QueryParams { query }))
+            .await
+            .map(|ids| ids.unwrap_or_default())
+    }
+
+    /// Field names of the model in their order
+    pub async fn model_field_names(&self, model_name: &str) -> Result<Vec<String>> {
+        self.invoke::<_, Vec<String>>("modelFieldNames", Some(ModelParams { model_name }))
+            .await
+            .map(|names| names.unwrap_or_default())
+    }
+
+    /// Stores the file in the media folder of the collection, replacing a file with the same name
+    pub async fn store_media_file(&self, filename: &str, data: &[u8]) -> Result<()> {
+        self.invoke::<_, serde_json::Value>("storeMediaFile", Some(MediaFileParams {
+            filename,
+            data: base64::encode(data),
+        }))
+            .await
             .map(|_| ())
-            .map_err(|e| Error::Reqwest { e, path })
     }
+
+    /// Runs the same action with each of `params` in a single request.
+    /// Results are returned in the order of `params`.
+    pub async fn multi<P: Serialize, R: DeserializeOwned>(&self, action: &str, params: Vec<P>) -> Result<Vec<Result<Option<R>>>> {
+        let expected = params.len();
+        let actions = params.into_iter()
+            .map(|p| Action {
+                version: API_VERSION,
+                action: action.to_string(),
+                params: Some(p),
+            })
+            .collect();
+        let responses = self.invoke::<_, Vec<Response<R>>>("multi", Some(MultiParams { actions }))
+            .await?
+            .unwrap_or_default();
+        if responses.len() != expected {
+            return Err(Error::AnkiConnect {
+                message: format!("multi returned {} results for {} actions", responses.len(), expected)
+            });
+        }
+        Ok(responses.into_iter().map(|r| r.into_result()).collect())
+    }
+}
+
+/// `addNote` request of the note as it is sent to AnkiConnect
+pub fn add_note_request(note: Note) -> Result<String> {
+    request_json("addNote", NoteParams { note })
+}
+
+/// `updateNoteFields` request of the note as it is sent to AnkiConnect
+pub fn update_note_fields_request(id: u64, note: Note) -> Result<String> {
+    request_json("updateNoteFields", NoteUpdateParams { note: NoteUpdate::new(id, note) })
+}
+
+fn request_json<P: Serialize>(action: &str, params: P) -> Result<String> {
+    let action = Action {
+        version: API_VERSION,
+        action: action.to_string(),
+        params: Some(params),
+    };
+    serde_json::to_string(&action)
+        .map_err(|e| Error::DeserializationError { e, message: format!("{} request", action.action) })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::lib::template::DuplicateOptions;
+    use crate::lib::testing::anki::FakeAnki;
+
+    #[test]
+    fn test_response_error() {
+        let response: Response<u64> = serde_json::from_str(
+            r#"{"result": null, "error": "cannot create note because it is a duplicate"}"#
+        ).unwrap();
+        match response.into_result() {
+            Err(Error::AnkiConnect { message }) => assert_eq!(message, "cannot create note because it is a duplicate"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_response_result() {
+        let response: Response<u64> = serde_json::from_str(
+            r#"{"result": 1496198395707, "error": null}"#
+        ).unwrap();
+        assert_eq!(response.into_result().unwrap(), Some(1496198395707));
+    }
+
+    #[test]
+    fn test_multi_response() {
+        let response: Response<Vec<Response<u64>>> = serde_json::from_str(
+            r#"{"result": [{"result": 1, "error": null}, {"result": null, "error": "deck was not found: Missing"}], "error": null}"#
+        ).unwrap();
+        let results = response.into_result().unwrap().unwrap();
+        assert_eq!(results.len(), 2);
+        let results = results.into_iter().map(|r| r.into_result()).collect::<Vec<_>>();
+        assert_eq!(results[0].as_ref().unwrap(), &Some(1));
+        assert!(matches!(&results[1], Err(Error::AnkiConnect { message }) if message == "deck was not found: Missing"));
+    }
+
+    #[test]
+    fn test_update_note_fields_request() {
+        let mut note = note("Default", "deter");
+        note.audio.clear();
+        assert_eq!(
+            update_note_fields_request(1496198395707, note).unwrap(),
+            r#"{"version":6,"action":"updateNoteFields","params":{"note":{"id":1496198395707,"fields":{"Back":"удерживать","Front":"deter"}}}}"#
+        );
+    }
+
+    fn note(deck: &str, front: &str) -> Note {
+        let mut fields = Fields::new();
+        fields.insert("Front".to_string(), front.to_string());
+        fields.insert("Back".to_string(), "удерживать".to_string());
+        Note {
+            deck_name: deck.to_string(),
+            model_name: "Basic".to_string(),
+            fields,
+            options: Options {
+                allow_duplicate: false,
+                duplicate_scope: "deck".to_string(),
+                duplicate_scope_options: None,
+            },
+            tags: vec!["skyeng".to_string()],
+            audio: vec![Attachment {
+                url: "https://d2fmfepycn0xw0.cloudfront.net?text=deter".to_string(),
+                filename: "skyeng_210809.mp3".to_string(),
+                fields: vec!["Back".to_string()],
+            }],
+            picture: vec![],
+        }
+    }
 
     #[tokio::test]
     async fn test_anki_sync() {
-        let anki = Anki::new("http://10.43.149.198".to_string());
+        let fake = FakeAnki::start().await;
+        let anki = Anki::new(fake.url.clone());
+        assert_eq!(anki.version().await.unwrap(), 6);
         anki.sync().await.unwrap();
+        assert_eq!(fake.syncs(), 1);
+        assert!(anki.load_profile("Kid").await.is_err());
+        fake.add_profile("Kid");
+        anki.load_profile("Kid").await.unwrap();
+        assert_eq!(fake.profile(), "Kid");
+    }
+
+    #[tokio::test]
+    async fn test_add_note() {
+        let fake = FakeAnki::start().await;
+        let anki = Anki::new(fake.url.clone());
+        let error = |result: Result<u64>| match result {
+            Err(Error::AnkiConnect { message }) => message,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert_eq!(error(anki.add_note(note("Skyeng", "deter")).await), "deck was not found: Skyeng");
+
+        anki.create_deck("Skyeng::Communication").await.unwrap();
+        assert_eq!(anki.deck_names().await.unwrap(), fake.decks());
+        assert_eq!(fake.decks(), vec!["Default", "Skyeng", "Skyeng::Communication"]);
+        let id = anki.add_note(note("Skyeng", "deter")).await.unwrap();
+        assert_eq!(error(anki.add_note(note("Skyeng", "deter")).await), "cannot create note because it is a duplicate");
+        anki.add_note(note("Skyeng::Communication", "deter")).await.unwrap();
+
+        let mut unknown = note("Skyeng", "chat");
+        unknown.fields.insert("Extra".to_string(), "".to_string());
+        assert_eq!(error(anki.add_note(unknown).await), "field was not found in model Basic: Extra");
+
+        let notes = fake.notes();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].id, id);
+        assert_eq!(notes[0].fields["Back"], "удерживать[sound:skyeng_210809.mp3]");
+        assert_eq!(notes[0].tags, vec!["skyeng"]);
+        assert!(fake.media().contains_key("skyeng_210809.mp3"));
+    }
+
+    #[tokio::test]
+    async fn test_multi_and_find_notes() {
+        let fake = FakeAnki::start().await;
+        let anki = Anki::new(fake.url.clone());
+        let results = anki.add_notes(vec![note("Default", "deter"), note("Default", "deter"), note("Default", "chat")])
+            .await
+            .unwrap();
+        assert!(results[0].is_ok() && results[1].is_err() && results[2].is_ok());
+
+        let query = DuplicateOptions::default().query(&note("Default", "deter"), "Front").unwrap();
+        let found = anki.find_notes(&query).await.unwrap();
+        assert_eq!(found, vec![*results[0].as_ref().unwrap()]);
+        assert_eq!(anki.find_notes("\"deck:Default\" \"Front:det_r\"").await.unwrap().len(), 1);
+        assert!(anki.find_notes("\"deck:Skyeng\"").await.unwrap().is_empty());
+
+        // the sound is linked into the replaced field again
+        let mut updated = note("Default", "deter");
+        updated.fields.insert("Back".to_string(), "сдерживать".to_string());
+        anki.update_note_fields(found[0], updated).await.unwrap();
+        assert_eq!(fake.notes()[0].fields["Back"], "сдерживать[sound:skyeng_210809.mp3]");
+        assert!(anki.update_note_fields(1, note("Default", "deter")).await.is_err());
+
+        anki.store_media_file("skyeng_1.png", b"png").await.unwrap();
+        assert_eq!(fake.media()["skyeng_1.png"], b"png");
+        assert_eq!(anki.model_field_names("Cloze").await.unwrap(), vec!["Text", "Extra"]);
+        fake.add_model("Skyeng", &["Word", "Meaning"]);
+        assert_eq!(anki.model_field_names("Skyeng").await.unwrap(), vec!["Word", "Meaning"]);
     }
 }
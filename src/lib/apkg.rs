@@ -0,0 +1,511 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use zip::write::FileOptions;
+use zip::ZipWriter;
+use super::anki::Note;
+use super::errors::{Error, Result};
+use super::template::NoteTemplate;
+
+const DEFAULT_DECK: &str = "Default";
+const DEFAULT_DECK_ID: i64 = 1;
+const FIELD_SEPARATOR: &str = "\u{1f}";
+/// Characters of the base91 GUIDs of Anki
+const GUID_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!#$%&()*+,-./:;<=>?@[]^_`{|}~";
+
+/// Collection of the legacy schema 11, which every Anki version imports
+const SCHEMA: &str = "
+    CREATE TABLE col (id integer primary key, crt integer not null, mod integer not null, scm integer not null,
+        ver integer not null, dty integer not null, usn integer not null, ls integer not null,
+        conf text not null, models text not null, decks text not null, dconf text not null, tags text not null);
+    CREATE TABLE notes (id integer primary key, guid text not null, mid integer not null, mod integer not null,
+        usn integer not null, tags text not null, flds text not null, sfld integer not null,
+        csum integer not null, flags integer not null, data text not null);
+    CREATE TABLE cards (id integer primary key, nid integer not null, did integer not null, ord integer not null,
+        mod integer not null, usn integer not null, type integer not null, queue integer not null,
+        due integer not null, ivl integer not null, factor integer not null, reps integer not null,
+        lapses integer not null, left integer not null, odue integer not null, odid integer not null,
+        flags integer not null, data text not null);
+    CREATE TABLE revlog (id integer primary key, cid integer not null, usn integer not null, ease integer not null,
+        ivl integer not null, lastIvl integer not null, factor integer not null, time integer not null,
+        type integer not null);
+    CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+    CREATE INDEX ix_notes_usn on notes (usn);
+    CREATE INDEX ix_cards_usn on cards (usn);
+    CREATE INDEX ix_revlog_usn on revlog (usn);
+    CREATE INDEX ix_cards_nid on cards (nid);
+    CREATE INDEX ix_cards_sched on cards (did, queue, due);
+    CREATE INDEX ix_revlog_cid on revlog (cid);
+    CREATE INDEX ix_notes_csum on notes (csum);
+";
+
+/// Anki package (`.apkg`) with the notes of the meanings, imported by Anki without AnkiConnect.
+///
+/// GUIDs of the notes are derived from the meaning ids, so importing a newer package
+/// updates the notes of an older one instead of adding duplicates.
+pub struct Package {
+    model: Model,
+    notes: Vec<PackageNote>,
+    /// file name -> content
+    media: BTreeMap<String, Vec<u8>>,
+}
+
+/// Note type of the package, built from the template as the package cannot look into the collection
+struct Model {
+    id: i64,
+    name: String,
+    fields: Vec<String>,
+    /// field with the cloze deletions, `None` for a standard note type
+    cloze: Option<String>,
+}
+
+struct PackageNote {
+    guid: String,
+    deck: String,
+    fields: Vec<String>,
+    tags: Vec<String>,
+}
+
+impl Package {
+    pub fn new(template: &NoteTemplate) -> Self {
+        Self {
+            model: Model::new(template),
+            notes: Vec::new(),
+            media: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the note of the meaning with its media files.
+    /// Attachments left in the note are dropped, `MediaCache::bundle` links them to the fields.
+    pub fn add(&mut self, meaning_id: u64, note: Note, files: Vec<(String, Vec<u8>)>) {
+        let fields = self.model.fields.iter()
+            .map(|name| note.fields.get(name).cloned().unwrap_or_default())
+            .collect();
+        self.notes.push(PackageNote {
+            guid: guid(meaning_id),
+            deck: note.deck_name,
+            fields,
+            tags: note.tags,
+        });
+        self.media.extend(files);
+    }
+
+    pub fn len(&self) -> usize {
+        self.notes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notes.is_empty()
+    }
+
+    /// Writes the package, replacing the file
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let collection = path.with_extension("anki2.tmp");
+        if collection.exists() {
+            fs::remove_file(&collection)?;
+        }
+        let result = self.write_collection(&collection)
+            .and_then(|_| self.write_zip(path, &fs::read(&collection)?));
+        fs::remove_file(&collection)?;
+        result
+    }
+
+    fn write_collection(&self, path: &Path) -> Result<()> {
+        let now = Utc::now();
+        let millis = now.timestamp_millis();
+        let secs = now.timestamp();
+        let connection = Connection::open(path).map_err(package_error)?;
+        connection.execute_batch(SCHEMA).map_err(package_error)?;
+
+        let decks = self.deck_ids();
+        connection.execute(
+            "INSERT INTO col VALUES (1, ?1, ?2, ?2, 11, 0, 0, 0, ?3, ?4, ?5, ?6, '{}')",
+            params![
+                secs - secs % 86400,
+                millis,
+                self.conf().to_string(),
+                json!({ self.model.id.to_string(): self.model.to_json(secs) }).to_string(),
+                decks_json(&decks, secs).to_string(),
+                json!({ "1": deck_conf() }).to_string(),
+            ],
+        ).map_err(package_error)?;
+
+        let mut card_id = millis;
+        for (position, note) in self.notes.iter().enumerate() {
+            let note_id = millis + position as i64;
+            let sort_field = strip_html(&note.fields[0]);
+            connection.execute(
+                "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, ?5, ?6, ?7, ?8, 0, '')",
+                params![
+                    note_id,
+                    note.guid,
+                    self.model.id,
+                    secs,
+                    format!(" {} ", note.tags.join(" ")),
+                    note.fields.join(FIELD_SEPARATOR),
+                    sort_field,
+                    checksum(&sort_field),
+                ],
+            ).map_err(package_error)?;
+            for ord in self.model.card_ords(&note.fields) {
+                connection.execute(
+                    "INSERT INTO cards VALUES (?1, ?2, ?3, ?4, ?5, -1, 0, 0, ?6, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+                    params![card_id, note_id, decks[&note.deck], ord, secs, position as i64 + 1],
+                ).map_err(package_error)?;
+                card_id += 1;
+            }
+        }
+        connection.close().map_err(|(_, e)| package_error(e))
+    }
+
+    fn write_zip(&self, path: &Path, collection: &[u8]) -> Result<()> {
+        let mut zip = ZipWriter::new(File::create(path)?);
+        let options = FileOptions::default();
+        zip.start_file("collection.anki2", options).map_err(package_error)?;
+        zip.write_all(collection)?;
+        let mut media = BTreeMap::new();
+        for (number, (name, data)) in self.media.iter().enumerate() {
+            zip.start_file(number.to_string(), options).map_err(package_error)?;
+            zip.write_all(data)?;
+            media.insert(number.to_string(), name.clone());
+        }
+        zip.start_file("media", options).map_err(package_error)?;
+        zip.write_all(json!(media).to_string().as_bytes())?;
+        zip.finish().map_err(package_error)?;
+        Ok(())
+    }
+
+    /// Ids of the decks of the notes and their parents
+    fn deck_ids(&self) -> BTreeMap<String, i64> {
+        let mut decks = BTreeMap::new();
+        decks.insert(DEFAULT_DECK.to_string(), DEFAULT_DECK_ID);
+        for note in self.notes.iter() {
+            let parts = note.deck.split("::").collect::<Vec<_>>();
+            for end in 1..=parts.len() {
+                let name = parts[..end].join("::");
+                let id = stable_id(&name);
+                decks.entry(name).or_insert(id);
+            }
+        }
+        decks
+    }
+
+    fn conf(&self) -> Value {
+        json!({
+            "nextPos": self.notes.len() + 1,
+            "estTimes": true,
+            "activeDecks": [DEFAULT_DECK_ID],
+            "sortType": "noteFld",
+            "timeLim": 0,
+            "sortBackwards": false,
+            "addToCur": true,
+            "curDeck": DEFAULT_DECK_ID,
+            "newSpread": 0,
+            "dueCounts": true,
+            "curModel": self.model.id.to_string(),
+            "collapseTime": 1200,
+        })
+    }
+}
+
+/// Fields of the note type in their order: the ones of the standard note types first,
+/// then the rest of the template fields by name
+pub(crate) fn model_fields(template: &NoteTemplate) -> Vec<String> {
+    let standard: &[&str] = match template.model_name.as_str() {
+        "Cloze" => &["Text", "Extra"],
+        name if name.starts_with("Basic") => &["Front", "Back"],
+        _ => &[],
+    };
+    let mut fields = standard.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+    let used = template.fields.keys()
+        .chain([&template.audio_field, &template.picture_field])
+        .chain(template.example_audio_field.iter());
+    for field in used {
+        if !fields.contains(field) {
+            fields.push(field.clone());
+        }
+    }
+    fields
+}
+
+impl Model {
+    fn new(template: &NoteTemplate) -> Self {
+        let fields = model_fields(template);
+        let cloze = template.fields.iter()
+            .find(|(_, value)| value.contains("{cloze}"))
+            .map(|(name, _)| name.clone())
+            .or_else(|| (template.model_name == "Cloze").then(|| fields[0].clone()));
+        Self {
+            id: stable_id(&format!("{}\u{1f}{}", template.model_name, fields.join("\u{1f}"))),
+            name: template.model_name.clone(),
+            fields,
+            cloze,
+        }
+    }
+
+    /// Card numbers of the note: one per cloze number or the single card of a standard note type
+    fn card_ords(&self, values: &[String]) -> Vec<u32> {
+        let field = match &self.cloze {
+            Some(field) => field,
+            None => return vec![0],
+        };
+        let value = self.fields.iter()
+            .position(|f| f == field)
+            .map(|i| values[i].as_str())
+            .unwrap_or_default();
+        let mut ords = value.split("{{c")
+            .skip(1)
+            .filter_map(|rest| rest.split_once("::"))
+            .filter_map(|(number, _)| number.parse::<u32>().ok())
+            .filter(|number| *number > 0)
+            .map(|number| number - 1)
+            .collect::<Vec<_>>();
+        ords.sort_unstable();
+        ords.dedup();
+        if ords.is_empty() {
+            ords.push(0);
+        }
+        ords
+    }
+
+    fn to_json(&self, secs: i64) -> Value {
+        let reference = |name: &String| match &self.cloze {
+            Some(cloze) if cloze == name => format!("{{{{cloze:{}}}}}", name),
+            _ => format!("{{{{{}}}}}", name),
+        };
+        let (front, back) = match &self.cloze {
+            Some(cloze) => (
+                reference(cloze),
+                self.fields.iter().filter(|f| *f != cloze).map(reference).collect::<Vec<_>>(),
+            ),
+            None => (
+                reference(&self.fields[0]),
+                self.fields[1..].iter().map(reference).collect::<Vec<_>>(),
+            ),
+        };
+        let answer = match &self.cloze {
+            Some(_) => format!("{}<br>\n{}", front, back.join("<br>\n")),
+            None => format!("{{{{FrontSide}}}}\n\n<hr id=answer>\n\n{}", back.join("<br>\n")),
+        };
+        let fields = self.fields.iter().enumerate()
+            .map(|(ord, name)| json!({
+                "name": name,
+                "ord": ord,
+                "sticky": false,
+                "rtl": false,
+                "font": "Arial",
+                "size": 20,
+                "media": [],
+            }))
+            .collect::<Vec<_>>();
+        json!({
+            "id": self.id,
+            "name": self.name,
+            "type": if self.cloze.is_some() { 1 } else { 0 },
+            "mod": secs,
+            "usn": -1,
+            "sortf": 0,
+            "did": DEFAULT_DECK_ID,
+            "tmpls": [{
+                "name": if self.cloze.is_some() { "Cloze" } else { "Card 1" },
+                "ord": 0,
+                "qfmt": front,
+                "afmt": answer,
+                "did": null,
+                "bqfmt": "",
+                "bafmt": "",
+            }],
+            "flds": fields,
+            "css": ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n color: black;\n background-color: white;\n}\n.cloze {\n font-weight: bold;\n color: blue;\n}\n",
+            "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+            "latexPost": "\\end{document}",
+            "latexsvg": false,
+            "req": [[0, "any", [0]]],
+            "tags": [],
+            "vers": [],
+        })
+    }
+}
+
+fn decks_json(decks: &BTreeMap<String, i64>, secs: i64) -> Value {
+    let decks = decks.iter()
+        .map(|(name, id)| (id.to_string(), json!({
+            "id": id,
+            "name": name,
+            "mod": secs,
+            "usn": -1,
+            "lrnToday": [0, 0],
+            "revToday": [0, 0],
+            "newToday": [0, 0],
+            "timeToday": [0, 0],
+            "collapsed": false,
+            "browserCollapsed": false,
+            "desc": "",
+            "dyn": 0,
+            "conf": 1,
+            "extendNew": 0,
+            "extendRev": 0,
+        })))
+        .collect::<serde_json::Map<_, _>>();
+    Value::Object(decks)
+}
+
+/// Default options group of the decks
+fn deck_conf() -> Value {
+    json!({
+        "id": 1,
+        "name": "Default",
+        "mod": 0,
+        "usn": 0,
+        "maxTaken": 60,
+        "autoplay": true,
+        "timer": 0,
+        "replayq": true,
+        "dyn": false,
+        "new": { "bury": false, "delays": [1.0, 10.0], "initialFactor": 2500, "ints": [1, 4, 0], "order": 1, "perDay": 20 },
+        "rev": { "bury": false, "ease4": 1.3, "ivlFct": 1.0, "maxIvl": 36500, "perDay": 200, "hardFactor": 1.2 },
+        "lapse": { "delays": [10.0], "leechAction": 1, "leechFails": 8, "minInt": 1, "mult": 0.0 },
+    })
+}
+
+/// GUID of the note of the meaning, the same in every package
+pub(crate) fn guid(meaning_id: u64) -> String {
+    let hash = Sha256::digest(format!("skynki:{}", meaning_id).as_bytes());
+    let mut value = u64::from_be_bytes(hash[..8].try_into().unwrap());
+    let mut guid = String::new();
+    while value > 0 {
+        guid.push(GUID_CHARS[(value % GUID_CHARS.len() as u64) as usize] as char);
+        value /= GUID_CHARS.len() as u64;
+    }
+    guid
+}
+
+/// Id of a deck or a note type, the same for the same name in every package
+fn stable_id(name: &str) -> i64 {
+    let hash = Sha256::digest(name.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes[2..].copy_from_slice(&hash[..6]);
+    // above the default deck id and within the integers of javascript
+    i64::from_be_bytes(bytes).max(DEFAULT_DECK_ID + 1)
+}
+
+/// First 8 hex digits of the SHA1 of the sort field, Anki looks for duplicates by it
+fn checksum(sort_field: &str) -> i64 {
+    let hash = Sha1::digest(sort_field.as_bytes());
+    u32::from_be_bytes(hash[..4].try_into().unwrap()) as i64
+}
+
+/// Text of the field without html tags, as Anki sorts and checks duplicates by it
+fn strip_html(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut in_tag = false;
+    for c in value.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+fn package_error(e: impl Display) -> Error {
+    Error::AnkiPackage { message: e.to_string() }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::io::Read;
+    use crate::lib::notes::AnkiPersistence;
+    use crate::lib::notes::NoteSettings;
+    use crate::lib::notes::test::sample_word;
+    use crate::lib::skyeng::test::sample_meaning;
+    use crate::lib::template::DeckOptions;
+    use super::*;
+
+    fn read(zip: &mut zip::ZipArchive<File>, name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        zip.by_name(name).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_guid() {
+        assert_eq!(guid(210809), guid(210809));
+        assert_ne!(guid(210809), guid(175493));
+        assert!(guid(210809).len() >= 9);
+    }
+
+    #[test]
+    fn test_card_ords() {
+        let model = Model::new(&NoteTemplate::default());
+        assert_eq!(model.fields, vec!["Text", "Extra"]);
+        let text = "{{c1::deter::удерживать}} {{c2::deter}} {{c1::again}}".to_string();
+        assert_eq!(model.card_ords(&[text, "".to_string()]), vec![0, 1]);
+
+        let template = NoteTemplate {
+            model_name: "Basic".to_string(),
+            fields: [("Back", "{translation}"), ("Front", "{text}")].iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..NoteTemplate::default()
+        };
+        let model = Model::new(&template);
+        assert_eq!(model.fields, vec!["Front", "Back", "Extra"]);
+        assert_eq!(model.cloze, None);
+        assert_eq!(model.card_ords(&[]), vec![0]);
+    }
+
+    #[test]
+    fn test_write_package() {
+        let settings = NoteSettings {
+            deck: DeckOptions { deck: "Skyeng".to_string(), subdecks: true, wordset_tags: true },
+            ..NoteSettings::default()
+        };
+        let meaning = sample_meaning();
+        let mut note = meaning.to_notes(&sample_word(), &settings);
+        note.audio.clear();
+        note.picture.clear();
+        note.fields.insert("Extra".to_string(), "[sound:deter.mp3]".to_string());
+        let mut package = Package::new(&settings.template);
+        package.add(meaning.id, note, vec![("deter.mp3".to_string(), b"sound".to_vec())]);
+        assert_eq!(package.len(), 1);
+
+        let path = env::temp_dir().join(format!("skynki-{}.apkg", std::process::id()));
+        package.write(&path).unwrap();
+        let mut zip = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(read(&mut zip, "media"), br#"{"0":"deter.mp3"}"#.to_vec());
+        assert_eq!(read(&mut zip, "0"), b"sound".to_vec());
+
+        let collection = path.with_extension("anki2");
+        fs::write(&collection, read(&mut zip, "collection.anki2")).unwrap();
+        let connection = Connection::open(&collection).unwrap();
+        let (note_guid, fields, tags): (String, String, String) = connection
+            .query_row("SELECT guid, flds, tags FROM notes", [], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap();
+        assert_eq!(note_guid, guid(meaning.id));
+        assert!(fields.contains("{{c1::deter"), "{}", fields);
+        assert!(fields.ends_with("\u{1f}[sound:deter.mp3]"), "{}", fields);
+        assert!(tags.contains("Communication"), "{}", tags);
+        let cards: i64 = connection.query_row("SELECT count(*) FROM cards", [], |r| r.get(0)).unwrap();
+        assert_eq!(cards, 1);
+        let decks: String = connection.query_row("SELECT decks FROM col", [], |r| r.get(0)).unwrap();
+        let decks = serde_json::from_str::<BTreeMap<String, Value>>(&decks).unwrap();
+        let names = decks.values().map(|d| d["name"].as_str().unwrap()).collect::<Vec<_>>();
+        assert!(names.contains(&"Skyeng::Communication::Informal communication"), "{:?}", names);
+        assert!(names.contains(&"Skyeng::Communication"), "{:?}", names);
+        drop(connection);
+        fs::remove_file(collection).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+}
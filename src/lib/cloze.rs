@@ -0,0 +1,106 @@
+use super::skyeng::Meaning;
+
+/// Builds the text of a Cloze note from the examples of a meaning.
+///
+/// Skyeng marks the learned word in the examples with brackets:
+/// `he wasn't [deterred]` becomes `he wasn't {{c1::deterred::удерживать}}`.
+#[derive(Debug, Clone, Default)]
+pub struct ClozeBuilder {
+    /// adds the word itself as a separate `c2` deletion
+    pub with_headword: bool,
+}
+
+impl ClozeBuilder {
+    pub fn new(with_headword: bool) -> Self {
+        Self {
+            with_headword,
+        }
+    }
+
+    pub fn build(&self, meaning: &Meaning) -> String {
+        let hint = &meaning.translation.text;
+        let mut lines = meaning.examples.iter()
+            .filter_map(|e| mask_brackets(&e.text, 1, hint))
+            .collect::<Vec<_>>();
+        // a note without deletions is refused by Anki, so the word becomes the only one
+        let headword_cloze = if lines.is_empty() { 1 } else { 2 };
+        if self.with_headword || lines.is_empty() {
+            lines.push(cloze(&meaning.text, headword_cloze, hint));
+        }
+        lines.join("<br>")
+    }
+}
+
+/// The hint is the last part of the deletion, so its `::` and `}}` are written as entities
+fn cloze(text: &str, number: u32, hint: &str) -> String {
+    let hint = hint.replace("::", ":&#58;").replace("}}", "}&#125;");
+    format!("{{{{c{}::{}::{}}}}}", number, text, hint)
+}
+
+/// Replaces every `[text]` with a cloze deletion, unmatched brackets are kept as is.
+/// `None` if there is nothing to mask.
+fn mask_brackets(text: &str, number: u32, hint: &str) -> Option<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut masked = false;
+    while let Some(start) = rest.find('[') {
+        match rest[start..].find(']') {
+            Some(len) => {
+                result.push_str(&rest[..start]);
+                result.push_str(&cloze(&rest[start + 1..start + len], number, hint));
+                rest = &rest[start + len + 1..];
+                masked = true;
+            }
+            None => break,
+        }
+    }
+    result.push_str(rest);
+    Some(result).filter(|_| masked)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lib::skyeng::test::sample_meaning;
+
+    #[test]
+    fn test_examples_cloze() {
+        let text = ClozeBuilder::default().build(&sample_meaning());
+        assert_eq!(
+            text,
+            "I told him I wasn't interested, but he wasn't {{c1::deterred::удерживать}}.<br>\
+            The rain didn’t {{c1::deter::удерживать}} people from coming to the game."
+        );
+    }
+
+    #[test]
+    fn test_headword_cloze() {
+        let text = ClozeBuilder::new(true).build(&sample_meaning());
+        assert!(text.ends_with("game.<br>{{c2::deter::удерживать}}"));
+    }
+
+    #[test]
+    fn test_cloze_without_examples() {
+        let mut meaning = sample_meaning();
+        meaning.examples.clear();
+        let text = ClozeBuilder::default().build(&meaning);
+        assert_eq!(text, "{{c1::deter::удерживать}}");
+    }
+
+    #[test]
+    fn test_unmatched_bracket() {
+        assert_eq!(mask_brackets("a [b] c [d", 1, "h"), Some("a {{c1::b::h}} c [d".to_string()));
+        assert_eq!(mask_brackets("a ]b[ c", 1, "h"), None);
+
+        // an example without a deletion does not replace the headword
+        let mut meaning = sample_meaning();
+        meaning.examples.truncate(1);
+        meaning.examples[0].text = "he wasn't [deterred".to_string();
+        assert_eq!(ClozeBuilder::default().build(&meaning), "{{c1::deter::удерживать}}");
+    }
+
+    #[test]
+    fn test_hint_escaped() {
+        assert_eq!(cloze("a", 1, "b::c}}"), "{{c1::a::b:&#58;c}&#125;}}");
+    }
+}
@@ -0,0 +1,215 @@
+use std::path::Path;
+use chrono::{DateTime, NaiveDateTime};
+use log::{info, warn};
+use super::accounts::{Account, Student};
+use super::apkg::Package;
+use super::config::Config;
+use super::errors::{Error, Result};
+use super::notes::{first_wordset_meanings, AnkiPersistence};
+use super::repository::{get_last_run, get_last_update, get_token, get_word_stats, save_last_update, CursorKey, Pool};
+use super::skyeng::{NewWords, WordsSource};
+use super::sync::get_meanings;
+use super::tsv::TextExport;
+
+/// Logs in with every account, the tokens are stored by the client
+pub async fn login(pool: &Pool, config: &Config) -> Result<()> {
+    for account in config.skyeng.accounts.iter() {
+        let mut skyeng = account.client(pool, &config.skyeng.urls)?;
+        skyeng.login().await?;
+        let expires = skyeng.get_token().map(|t| format_millis(t.expires));
+        println!("{}\tlogged in, token expires at {}", account.username, expires.unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Prints wordsets of the students: id, title and subtitle
+pub async fn wordsets(pool: &Pool, config: &Config, student: Option<u32>) -> Result<()> {
+    for (account, student) in students(config, student)? {
+        let mut skyeng = account.client(pool, &config.skyeng.urls)?;
+        for wordset in skyeng.get_word_sets(&student.id).await? {
+            println!("{}\t{}\t{}\t{}", student.id, wordset.id, wordset.title, wordset.subtitle);
+        }
+    }
+    Ok(())
+}
+
+/// Prints words of the students created after the time: creation time, meaning id and wordset
+pub async fn words(pool: &Pool, config: &Config, student: Option<u32>, after: Option<NaiveDateTime>) -> Result<()> {
+    for (account, student) in students(config, student)? {
+        let mut skyeng = account.client(pool, &config.skyeng.urls)?;
+        let mut words = skyeng.get_words(&student.id).await?.created_after(&after);
+        words.sort_by_key(|w| w.word.created());
+        for word in words {
+            println!("{}\t{}\t{}\t{}", student.id, word.word.created_at, word.word.meaning_id, word.wordset.title);
+        }
+    }
+    Ok(())
+}
+
+/// File written by the export
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// Anki package with the media
+    #[default]
+    Apkg,
+    /// tab separated text for the text importer of Anki, without media
+    Tsv,
+}
+
+/// Written notes of the export
+enum Export {
+    Apkg(Package),
+    Tsv(TextExport),
+}
+
+impl Export {
+    fn len(&self) -> usize {
+        match self {
+            Export::Apkg(package) => package.len(),
+            Export::Tsv(text) => text.len(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Export::Apkg(package) => package.is_empty(),
+            Export::Tsv(text) => text.is_empty(),
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        match self {
+            Export::Apkg(package) => package.write(path),
+            Export::Tsv(text) => text.write(path),
+        }
+    }
+}
+
+/// Writes notes of the students words created after the time into a file to be imported
+/// into Anki without AnkiConnect. Media of a package is bundled from the media cache, it is left out without the cache.
+/// Neither the cursors nor the stored words are changed.
+pub async fn export(pool: &Pool, config: &Config, student: Option<u32>, after: Option<NaiveDateTime>, format: ExportFormat, output: &Path) -> Result<()> {
+    let settings = config.note_settings();
+    let (mut export, mut media) = match format {
+        ExportFormat::Apkg => (Export::Apkg(Package::new(&settings.template)), config.media_cache()?),
+        ExportFormat::Tsv => (Export::Tsv(TextExport::new(&settings.template)), None),
+    };
+    if format == ExportFormat::Apkg && media.is_none() {
+        warn!("anki.media_cache is not set, the package is written without media");
+    }
+    for (account, student) in students(config, student)? {
+        let mut skyeng = account.client(pool, &config.skyeng.urls)?;
+        let words = skyeng.get_words(&student.id).await?.created_after(&after);
+        if words.is_empty() {
+            continue;
+        }
+        let data = words.iter().map(|w| w.word.clone()).collect::<Vec<_>>();
+        let meanings = get_meanings(&mut skyeng, &data).await?;
+        // a meaning of several wordsets is a single note with a single GUID
+        for (word, meaning) in first_wordset_meanings(&words, meanings) {
+            let mut note = meaning.to_notes(&word, &settings);
+            match &mut export {
+                Export::Apkg(package) => {
+                    let files = match media.as_mut() {
+                        Some(cache) => cache.bundle(&mut note).await?,
+                        None => Vec::new(),
+                    };
+                    package.add(meaning.id, note, files);
+                }
+                Export::Tsv(text) => text.add(meaning.id, note),
+            }
+        }
+    }
+    if export.is_empty() {
+        warn!("There are no words to export, the file has no notes");
+    }
+    export.write(output)?;
+    info!("{} notes written to {}", export.len(), output.display());
+    Ok(())
+}
+
+/// Moves the cursor of the student targets back, so the next sync looks at the words created after `to` again.
+/// Without `to` all the words are looked at, already exported ones are still skipped.
+pub fn reset_cursor(pool: &Pool, config: &Config, student: Option<u32>, target: Option<&str>, to: Option<NaiveDateTime>) -> Result<()> {
+    let to = to.unwrap_or(DateTime::UNIX_EPOCH.naive_utc());
+    let mut reset = 0;
+    for (account, student) in students(config, student)? {
+        for name in target_names(config, student).into_iter().filter(|n| target.is_none_or(|t| t == n)) {
+            save_last_update(pool, &CursorKey::new(&account.username, student.id, &name), &to)?;
+            println!("{}\t{}\tcursor reset to {}", student.id, name, to);
+            reset += 1;
+        }
+    }
+    if reset == 0 {
+        return Err(Error::UserError { message: "The target is not configured for the students" });
+    }
+    Ok(())
+}
+
+/// Prints the last sync run, the token expiry of the accounts, the cursors and counts of the stored words of their students
+pub fn status(pool: &Pool, config: &Config) -> Result<()> {
+    match get_last_run(pool)? {
+        Some(run) => println!(
+            "last sync at {}\tadded: {}, updated: {}, skipped: {}, failed: {}{}",
+            run.finished_at,
+            run.added,
+            run.updated,
+            run.skipped,
+            run.failed,
+            run.error.map(|e| format!(", error: {}", e)).unwrap_or_default(),
+        ),
+        None => println!("last sync: none"),
+    }
+    for account in config.skyeng.accounts.iter() {
+        let token = match get_token(pool, &account.username)? {
+            Some(token) if token.is_expired() => format!("expired at {}", format_millis(token.expires)),
+            Some(token) => format!("expires at {}", format_millis(token.expires)),
+            None => "none".to_string(),
+        };
+        println!("{}\ttoken {}", account.username, token);
+        for student in account.students.iter() {
+            for name in target_names(config, student) {
+                let cursor = get_last_update(pool, &CursorKey::new(&account.username, student.id, &name))?;
+                let stats = get_word_stats(pool, student.id.into(), &name)?;
+                println!(
+                    "  {} -> {}\tcursor: {}, words: {}, exported: {}, last export: {}",
+                    student.id,
+                    name,
+                    format_time(cursor),
+                    stats.total,
+                    stats.exported,
+                    format_time(stats.last_exported_at),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Configured students or the one with the id
+fn students(config: &Config, id: Option<u32>) -> Result<Vec<(&Account, &Student)>> {
+    let students = config.skyeng.accounts.iter()
+        .flat_map(|a| a.students.iter().map(move |s| (a, s)))
+        .filter(|(_, s)| id.is_none_or(|id| s.id == id))
+        .collect::<Vec<_>>();
+    if students.is_empty() {
+        return Err(Error::UserError { message: "The student is not configured" });
+    }
+    Ok(students)
+}
+
+fn target_names(config: &Config, student: &Student) -> Vec<String> {
+    student.targets.iter()
+        .map(|t| t.name(&config.anki.deck.deck))
+        .collect()
+}
+
+fn format_millis(millis: u128) -> String {
+    DateTime::from_timestamp_millis(millis as i64)
+        .map(|t| t.naive_utc().to_string())
+        .unwrap_or_default()
+}
+
+fn format_time(time: Option<NaiveDateTime>) -> String {
+    time.map(|t| t.to_string()).unwrap_or_else(|| "-".to_string())
+}
@@ -0,0 +1,220 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tokio::time::sleep;
+use super::accounts::SkyengClients;
+use super::config::{Config, SchedulingConfig};
+use super::errors::{Error, Result};
+use super::http::{self, ApiState};
+use super::repository::Pool;
+use super::sync::{sync_accounts, RunReport, StopSignal, SyncOptions};
+
+/// When the daemon starts the next run
+enum Schedule {
+    /// pause between the end of a run and the start of the next one
+    Interval(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    fn from_config(config: &SchedulingConfig) -> Result<Self> {
+        match (config.interval, &config.cron) {
+            (Some(secs), _) => Ok(Schedule::Interval(Duration::from_secs(secs))),
+            (None, Some(expression)) => cron::Schedule::from_str(expression)
+                .map(|schedule| Schedule::Cron(Box::new(schedule)))
+                .map_err(|_| Error::UserError { message: "scheduling.cron is not a valid cron expression" }),
+            (None, None) => Err(Error::UserError { message: "scheduling.interval or scheduling.cron must be set for the daemon" }),
+        }
+    }
+
+    /// The first run with an interval starts right away, with cron at the first matching time
+    fn first_delay(&self, now: DateTime<Utc>) -> Option<Duration> {
+        match self {
+            Schedule::Interval(_) => Some(Duration::ZERO),
+            Schedule::Cron(_) => self.next_delay(now),
+        }
+    }
+
+    /// `None` if the cron expression has no more matching times
+    fn next_delay(&self, now: DateTime<Utc>) -> Option<Duration> {
+        match self {
+            Schedule::Interval(interval) => Some(*interval),
+            Schedule::Cron(schedule) => schedule.after(&now)
+                .next()
+                .map(|next| (next - now).to_std().unwrap_or(Duration::ZERO)),
+        }
+    }
+}
+
+/// The current or the last finished run, as shown by the HTTP API
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunStatus {
+    pub running: bool,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub report: Option<RunReport>,
+    pub error: Option<String>,
+}
+
+/// Delay before the next attempt after the number of failed runs in a row
+fn retry_delay(config: &SchedulingConfig, failures: u32) -> Duration {
+    let factor = 2u64.saturating_pow(failures.saturating_sub(1));
+    Duration::from_secs(config.retry_delay.saturating_mul(factor).min(config.max_retry_delay))
+}
+
+/// Runs the sync on the schedule until SIGTERM or Ctrl+C.
+///
+/// Runs never overlap, the next one is scheduled once the previous has finished.
+/// Skyeng clients live between the runs, so their cookies and tokens are reused.
+/// A failed run is retried with an exponential backoff instead of waiting for the schedule.
+/// A signal during a run stops it after the current note.
+/// With `http.listen` set the HTTP API is served as well, `POST /sync` starts a run right away
+/// and the schedule may be omitted.
+pub async fn run(pool: &Pool, config: &Config) -> Result<()> {
+    let schedule = match Schedule::from_config(&config.scheduling) {
+        Ok(schedule) => Some(schedule),
+        Err(_) if config.scheduling.cron.is_none() && config.http.listen.is_some() => None,
+        Err(e) => return Err(e),
+    };
+    let stop = StopSignal::default();
+    listen_for_signals(stop.clone())?;
+    let status = Arc::new(Mutex::new(RunStatus::default()));
+    let trigger = Arc::new(Notify::new());
+    if let Some(listen) = config.http.listen {
+        let state = ApiState {
+            pool: pool.clone(),
+            config: Arc::new(config.clone()),
+            status: status.clone(),
+            trigger: trigger.clone(),
+        };
+        http::spawn(listen, state, stop.clone()).await?;
+    }
+    let options = SyncOptions {
+        resync: config.anki.resync,
+        dry_run: None,
+        stop: stop.clone(),
+    };
+    let settings = config.note_settings();
+    let mut clients = SkyengClients::new(config.skyeng.urls.clone());
+    let mut failures = 0;
+    info!("Daemon started");
+
+    let mut delay = schedule.as_ref().and_then(|s| s.first_delay(Utc::now()));
+    loop {
+        tokio::select! {
+            _ = sleep(delay.unwrap_or(Duration::MAX)), if delay.is_some() => {}
+            _ = trigger.notified() => info!("Sync requested over HTTP"),
+            _ = stop.stopped() => break,
+        }
+        {
+            let mut status = status.lock().unwrap();
+            status.running = true;
+            status.started_at = Some(Utc::now());
+        }
+        let result = sync_accounts(pool, &config.skyeng.accounts, &mut clients, &settings, config.anki.media_cache.as_deref(), &options).await;
+        let mut status = status.lock().unwrap();
+        status.running = false;
+        status.finished_at = Some(Utc::now());
+        delay = match result {
+            Ok(report) => {
+                failures = 0;
+                info!("Sync finished. {}", report);
+                status.report = Some(report);
+                status.error = None;
+                schedule.as_ref().and_then(|s| s.next_delay(Utc::now()))
+            }
+            Err(e) => {
+                failures += 1;
+                let retry = retry_delay(&config.scheduling, failures);
+                error!("Sync failed: {}. Retrying in {} seconds", e, retry.as_secs());
+                status.report = None;
+                status.error = Some(e.to_string());
+                Some(retry)
+            }
+        };
+        drop(status);
+        if delay.is_none() && schedule.is_some() {
+            if config.http.listen.is_none() {
+                info!("The cron expression has no more matching times");
+                break;
+            }
+            info!("The cron expression has no more matching times, waiting for HTTP requests");
+        }
+    }
+    info!("Daemon stopped");
+    Ok(())
+}
+
+fn listen_for_signals(stop: StopSignal) -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())
+        .map_err(|e| Error::UnexpectedError(Box::new(e)))?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => info!("SIGTERM received, stopping after the current note"),
+            _ = tokio::signal::ctrl_c() => info!("Ctrl+C received, stopping after the current note"),
+        }
+        stop.stop();
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_interval_schedule() {
+        let config = SchedulingConfig {
+            interval: Some(600),
+            ..SchedulingConfig::default()
+        };
+        let schedule = Schedule::from_config(&config).unwrap();
+        let now = time("2022-02-10T10:40:57+00:00");
+        assert_eq!(schedule.first_delay(now), Some(Duration::ZERO));
+        assert_eq!(schedule.next_delay(now), Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn test_cron_schedule() {
+        let config = SchedulingConfig {
+            cron: Some("0 0 * * * *".to_string()),
+            ..SchedulingConfig::default()
+        };
+        let schedule = Schedule::from_config(&config).unwrap();
+        let now = time("2022-02-10T10:40:57+00:00");
+        assert_eq!(schedule.first_delay(now), Some(Duration::from_secs(19 * 60 + 3)));
+        assert!(Schedule::from_config(&SchedulingConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let config = SchedulingConfig {
+            retry_delay: 60,
+            max_retry_delay: 300,
+            ..SchedulingConfig::default()
+        };
+        let delays = (1..=5)
+            .map(|failures| retry_delay(&config, failures).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![60, 120, 240, 300, 300]);
+    }
+
+    #[tokio::test]
+    async fn test_stop_signal() {
+        let stop = StopSignal::default();
+        let waiting = stop.clone();
+        let waiter = tokio::spawn(async move { waiting.stopped().await });
+        stop.stop();
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+        assert!(stop.is_stopped());
+    }
+}
@@ -4,19 +4,20 @@ use diesel::r2d2::{
     ConnectionManager,
     Pool
 };
-use std::env;
+use super::config::DatabaseConfig;
 use super::errors::Error;
 
 #[derive(Clone, Debug)]
 pub struct DbConfig;
 
 impl DbConfig {
-    pub fn get_pool() -> Pool<ConnectionManager<PgConnection>> {
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    pub fn get_pool(config: &DatabaseConfig) -> Result<Pool<ConnectionManager<PgConnection>>, Error> {
+        let database_url = config.url.clone()
+            .ok_or(Error::UserError { message: "DATABASE_URL must be set" })?;
         let manager = ConnectionManager::<PgConnection>::new(database_url);
-        r2d2::Pool::builder().max_size(15)
-            .build(manager)
-            .expect("Failed to create pool.")
+        let pool = r2d2::Pool::builder().max_size(config.pool_size)
+            .build(manager)?;
+        Ok(pool)
     }
 
     pub fn test_connection(pool: Pool<ConnectionManager<PgConnection>>) -> Result<(), Error> {
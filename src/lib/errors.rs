@@ -36,6 +36,17 @@ pub enum Error {
         message: String
     },
 
+    #[error("AnkiConnect error: {message}")]
+    AnkiConnect {
+        message: String
+    },
+
+    #[error("File error: {source}")]
+    File {
+        #[from]
+        source: std::io::Error
+    },
+
     #[error("Db error: {source}")]
     DbError {
         #[from]
@@ -48,8 +59,46 @@ pub enum Error {
         source: diesel::result::Error
     },
 
+    #[error("Invalid configuration:\n  {}", .errors.join("\n  "))]
+    InvalidConfig {
+        errors: Vec<String>
+    },
+
+    #[error("Package error: {message}")]
+    AnkiPackage {
+        message: String
+    },
+
+    #[error("Migration error: {source}")]
+    Migration {
+        #[from]
+        source: diesel_migrations::RunMigrationsError
+    },
+
     #[error(transparent)]
     UnexpectedError(#[from] Box<dyn std::error::Error>),
 }
 
+impl Error {
+    /// Process exit code, which tells what part of the sync has failed
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::UserError { .. }
+            | Error::InvalidConfig { .. } => 2,
+            Error::Reqwest { .. }
+            | Error::ReqwestIo { .. }
+            | Error::HttpParsingError { .. }
+            | Error::ServerError { .. }
+            | Error::DeserializationError { .. } => 3,
+            Error::AnkiConnect { .. } => 4,
+            Error::DbError { .. }
+            | Error::DieselError { .. }
+            | Error::Migration { .. } => 5,
+            Error::File { .. }
+            | Error::AnkiPackage { .. } => 6,
+            Error::UnexpectedError(_) => 1,
+        }
+    }
+}
+
 pub type Result<T, E = Error> = std::result::Result<T, E>;
\ No newline at end of file
@@ -0,0 +1,187 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use async_trait::async_trait;
+use serde::Serialize;
+use super::anki::Note;
+use super::errors::{Error, Result};
+use super::sink::{Flashcard, FlashcardSink, Pushed};
+use super::sync::StopSignal;
+use super::template::NoteTemplate;
+use super::tsv::TextExport;
+
+/// Outcome of a card written to a file, an updated card is written once more
+pub(crate) fn written(card: &Flashcard) -> Pushed {
+    match card.note_id {
+        Some(_) => Pushed::Updated,
+        None => Pushed::Added(None),
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+/// Appends the notes to a file for the text importer of Anki
+pub struct CsvSink {
+    path: PathBuf,
+    template: NoteTemplate,
+}
+
+impl CsvSink {
+    pub fn new(path: PathBuf, template: &NoteTemplate) -> Self {
+        Self {
+            path,
+            template: template.clone(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl FlashcardSink for CsvSink {
+    async fn push(&mut self, cards: Vec<Flashcard>, _stop: &StopSignal) -> Result<Vec<Pushed>> {
+        let outcomes = cards.iter().map(written).collect();
+        let mut text = TextExport::new(&self.template);
+        for card in cards {
+            text.add(card.meaning.id, card.note);
+        }
+        text.append(&self.path)?;
+        Ok(outcomes)
+    }
+}
+
+/// Line of the JSON file
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonCard<'a> {
+    meaning_id: u64,
+    text: &'a str,
+    wordset_id: u32,
+    wordset: &'a str,
+    created_at: &'a str,
+    /// id of the updated note
+    note_id: Option<u64>,
+    note: &'a Note,
+}
+
+/// Appends a JSON object per note, the note is the one AnkiConnect would get
+pub struct JsonSink {
+    path: PathBuf,
+}
+
+impl JsonSink {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait(?Send)]
+impl FlashcardSink for JsonSink {
+    async fn push(&mut self, cards: Vec<Flashcard>, _stop: &StopSignal) -> Result<Vec<Pushed>> {
+        let mut lines = String::new();
+        for card in cards.iter() {
+            let line = serde_json::to_string(&JsonCard {
+                meaning_id: card.meaning.id,
+                text: &card.meaning.text,
+                wordset_id: card.word.wordset.id,
+                wordset: &card.word.wordset.title,
+                created_at: &card.word.word.created_at,
+                note_id: card.note_id,
+                note: &card.note,
+            }).map_err(|e| Error::DeserializationError { e, message: format!("meaning {}", card.meaning.id) })?;
+            lines.push_str(&line);
+            lines.push('\n');
+        }
+        open_append(&self.path)?.write_all(lines.as_bytes())?;
+        Ok(cards.iter().map(written).collect())
+    }
+}
+
+/// Appends a section per note with its fields and links to the media
+pub struct MarkdownSink {
+    path: PathBuf,
+}
+
+impl MarkdownSink {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait(?Send)]
+impl FlashcardSink for MarkdownSink {
+    async fn push(&mut self, cards: Vec<Flashcard>, _stop: &StopSignal) -> Result<Vec<Pushed>> {
+        let text = cards.iter().map(to_markdown).collect::<String>();
+        open_append(&self.path)?.write_all(text.as_bytes())?;
+        Ok(cards.iter().map(written).collect())
+    }
+}
+
+fn to_markdown(card: &Flashcard) -> String {
+    let note = &card.note;
+    let mut text = format!("## {}\n\n", card.meaning.text);
+    text.push_str(&format!("Deck: {}", note.deck_name));
+    if !note.tags.is_empty() {
+        let tags = note.tags.iter().map(|t| format!("#{}", t)).collect::<Vec<_>>();
+        text.push_str(&format!(" {}", tags.join(" ")));
+    }
+    text.push_str("\n\n");
+    for (name, value) in note.fields.iter().filter(|(_, v)| !v.is_empty()) {
+        text.push_str(&format!("**{}**: {}\n\n", name, value));
+    }
+    for picture in note.picture.iter() {
+        text.push_str(&format!("![{}]({})\n\n", picture.filename, picture.url));
+    }
+    for audio in note.audio.iter() {
+        text.push_str(&format!("[{}]({})\n\n", audio.filename, audio.url));
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use crate::lib::notes::test::sample_word;
+    use crate::lib::notes::{AnkiPersistence, NoteSettings};
+    use crate::lib::skyeng::test::sample_meaning;
+    use super::*;
+
+    fn card() -> Flashcard {
+        let word = sample_word();
+        let meaning = sample_meaning();
+        Flashcard {
+            note: meaning.to_notes(&word, &NoteSettings::default()),
+            word,
+            meaning,
+            note_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_csv_sink() {
+        let path = env::temp_dir().join(format!("skynki-sink-{}.txt", std::process::id()));
+        let mut sink = CsvSink::new(path.clone(), &NoteTemplate::default());
+        sink.push(vec![card()], &StopSignal::default()).await.unwrap();
+        let mut updated = card();
+        updated.note_id = Some(1);
+        let outcomes = sink.push(vec![updated], &StopSignal::default()).await.unwrap();
+        assert!(matches!(outcomes[0], Pushed::Updated));
+
+        // the header is written once, the same GUID lets Anki update the note
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(text.matches("#separator:tab").count(), 1);
+        let rows = text.lines().filter(|l| !l.starts_with('#')).collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0], rows[1]);
+    }
+
+    #[test]
+    fn test_markdown() {
+        let text = to_markdown(&card());
+        assert!(text.starts_with("## deter\n\nDeck: Default #skyeng\n\n"), "{}", text);
+        assert!(text.contains("**Text**: "), "{}", text);
+        assert!(text.contains("(https://"), "{}", text);
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::NaiveDateTime;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use super::anki::Anki;
+use super::config::Config;
+use super::daemon::RunStatus;
+use super::db_config::DbConfig;
+use super::errors::{Error, Result};
+use super::metrics::metrics;
+use super::repository::{get_last_update, get_words, CursorKey, Pool, Word};
+use super::sync::StopSignal;
+
+/// Everything the handlers share with the daemon
+#[derive(Clone)]
+pub struct ApiState {
+    pub pool: Pool,
+    pub config: Arc<Config>,
+    /// the last run, updated by the daemon
+    pub status: Arc<Mutex<RunStatus>>,
+    /// wakes the daemon up to run right away
+    pub trigger: Arc<Notify>,
+}
+
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/sync", post(sync))
+        .route("/status", get(status))
+        .route("/healthz", get(healthz))
+        .route("/words", get(words))
+        .route("/metrics", get(prometheus_metrics))
+        .with_state(state)
+}
+
+/// Serves the API in the background until the stop signal
+pub async fn spawn(listen: SocketAddr, state: ApiState, stop: StopSignal) -> Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .map_err(|e| Error::UnexpectedError(Box::new(e)))?;
+    info!("HTTP API listens on {}", listen);
+    tokio::spawn(async move {
+        let server = axum::serve(listener, router(state))
+            .with_graceful_shutdown(async move { stop.stopped().await });
+        if let Err(e) = server.await {
+            error!("HTTP API failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Handler error, reported as `500` with the message
+struct ApiError(Error);
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        ApiError(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(serde_json::json!({ "error": self.0.to_string() }));
+        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+    }
+}
+
+/// Runs the sync as soon as the current run, if any, is finished
+async fn sync(State(state): State<ApiState>) -> impl IntoResponse {
+    state.trigger.notify_one();
+    (StatusCode::ACCEPTED, Json(serde_json::json!({ "status": "scheduled" })))
+}
+
+#[derive(Serialize)]
+struct Status {
+    run: RunStatus,
+    cursors: Vec<CursorStatus>,
+}
+
+#[derive(Serialize)]
+struct CursorStatus {
+    login: String,
+    student: u32,
+    target: String,
+    last_update: Option<NaiveDateTime>,
+}
+
+async fn status(State(state): State<ApiState>) -> std::result::Result<Json<Status>, ApiError> {
+    let run = state.status.lock().unwrap().clone();
+    let mut cursors = Vec::new();
+    for account in state.config.skyeng.accounts.iter() {
+        for student in account.students.iter() {
+            for target in student.targets.iter() {
+                let target = target.name(&state.config.anki.deck.deck);
+                let key = CursorKey::new(&account.username, student.id, &target);
+                cursors.push(CursorStatus {
+                    login: account.username.clone(),
+                    student: student.id,
+                    target,
+                    last_update: get_last_update(&state.pool, &key)?,
+                });
+            }
+        }
+    }
+    Ok(Json(Status { run, cursors }))
+}
+
+/// `200` if the database, AnkiConnect of every target with an Anki sink and the paths of the file sinks are fine, `503` otherwise
+async fn healthz(State(state): State<ApiState>) -> impl IntoResponse {
+    let mut healthy = true;
+    let database = match DbConfig::test_connection(state.pool.clone()) {
+        Ok(_) => "ok".to_string(),
+        Err(e) => {
+            healthy = false;
+            e.to_string()
+        }
+    };
+    let (sinks_healthy, anki, files) = check_sinks(&state.config).await;
+    let code = if healthy && sinks_healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (code, Json(serde_json::json!({ "database": database, "anki": anki, "files": files })))
+}
+
+/// Probes AnkiConnect of the targets with an Anki sink and the paths of the file sinks
+async fn check_sinks(config: &Config) -> (bool, BTreeMap<String, String>, BTreeMap<String, String>) {
+    let mut healthy = true;
+    let mut anki = BTreeMap::new();
+    let mut files = BTreeMap::new();
+    let targets = config.skyeng.accounts.iter()
+        .flat_map(|a| a.students.iter())
+        .flat_map(|s| s.targets.iter());
+    for target in targets {
+        for sink in target.sinks.iter() {
+            if let Some(path) = sink.path() {
+                let result = match sink.check_writable() {
+                    Ok(_) => "ok".to_string(),
+                    Err(e) => {
+                        healthy = false;
+                        e.to_string()
+                    }
+                };
+                files.insert(path.display().to_string(), result);
+            } else if !anki.contains_key(&target.anki_url) {
+                let result = match Anki::new(target.anki_url.clone()).version().await {
+                    Ok(version) => format!("ok, version {}", version),
+                    Err(e) => {
+                        healthy = false;
+                        e.to_string()
+                    }
+                };
+                anki.insert(target.anki_url.clone(), result);
+            }
+        }
+    }
+    (healthy, anki, files)
+}
+
+#[derive(Deserialize)]
+struct WordsQuery {
+    student: u32,
+}
+
+async fn words(State(state): State<ApiState>, Query(query): Query<WordsQuery>) -> std::result::Result<Json<Vec<Word>>, ApiError> {
+    Ok(Json(get_words(&state.pool, query.student.into())?))
+}
+
+/// Metrics in the Prometheus text format
+async fn prometheus_metrics() -> std::result::Result<impl IntoResponse, ApiError> {
+    let text = metrics().render()?;
+    Ok(([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], text))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::lib::accounts::{Account, Student, Target};
+    use crate::lib::sink::SinkConfig;
+    use crate::lib::testing::unused_pool;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sync_trigger() {
+        let state = ApiState {
+            pool: unused_pool(),
+            config: Arc::new(Config::default()),
+            status: Arc::new(Mutex::new(RunStatus::default())),
+            trigger: Arc::new(Notify::new()),
+        };
+        let trigger = state.trigger.clone();
+        let stop = StopSignal::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        spawn(address, state, stop.clone()).await.unwrap();
+
+        let client = reqwest::Client::new();
+        let response = client.post(format!("http://{}/sync", address)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+        tokio::time::timeout(Duration::from_secs(1), trigger.notified()).await.unwrap();
+
+        let response = client.get(format!("http://{}/status", address)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let status = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(status["run"]["running"], false);
+        assert_eq!(status["cursors"], serde_json::json!([]));
+
+        let response = client.get(format!("http://{}/healthz", address)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = client.get(format!("http://{}/metrics", address)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response.text().await.unwrap().contains("skynki_logins_total"));
+        stop.stop();
+    }
+
+    #[tokio::test]
+    async fn test_check_file_sinks() {
+        let missing = std::env::temp_dir().join("skynki-missing").join("words.txt");
+        let sinks = vec![
+            SinkConfig::Json { path: std::env::temp_dir().join("words.json") },
+            SinkConfig::Csv { path: missing.clone() },
+        ];
+        let mut config = Config::default();
+        config.skyeng.accounts.push(Account {
+            username: "user".to_string(),
+            password: "password".to_string(),
+            students: vec![Student { id: 1, targets: vec![Target { sinks, ..Target::default() }] }],
+        });
+
+        // file sinks don't need AnkiConnect, a file in a missing folder can't be created
+        let (healthy, anki, files) = check_sinks(&config).await;
+        assert!(!healthy);
+        assert!(anki.is_empty());
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[&std::env::temp_dir().join("words.json").display().to_string()], "ok");
+        assert_ne!(files[&missing.display().to_string()], "ok");
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use log::info;
+use sha2::{Digest, Sha256};
+use super::anki::{Anki, Attachment, Note};
+use super::errors::{Error, Result};
+
+const INDEX_FILE: &str = "index.json";
+
+/// Local copy of the sounds and images referenced by the notes.
+///
+/// Files are stored by the hash of their content, `index.json` maps source urls to them,
+/// so each url is downloaded only once. Media is uploaded to Anki with `storeMediaFile`,
+/// hence Anki itself never needs to reach the Skyeng CDN.
+pub struct MediaCache {
+    client: reqwest::Client,
+    dir: PathBuf,
+    /// url -> file name in the cache directory
+    index: HashMap<String, String>,
+    /// file names already stored in Anki by this instance
+    uploaded: HashSet<String>,
+}
+
+impl MediaCache {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let index_path = dir.join(INDEX_FILE);
+        let index = if index_path.exists() {
+            let json = fs::read_to_string(&index_path)?;
+            serde_json::from_str(&json)
+                .map_err(|e| Error::DeserializationError { e, message: json.clone() })?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            client: reqwest::Client::new(),
+            dir,
+            index,
+            uploaded: HashSet::new(),
+        })
+    }
+
+    /// Content of the url, downloaded only if it is not cached yet
+    pub async fn fetch(&mut self, url: &str, filename: &str) -> Result<Vec<u8>> {
+        if let Some(file) = self.index.get(url) {
+            let path = self.dir.join(file);
+            if path.exists() {
+                return Ok(fs::read(path)?);
+            }
+        }
+        let path = url.to_string();
+        info!("Downloading {}", path);
+        let data = self.client.get(url)
+            .send().await
+            .and_then(|rs| rs.error_for_status())
+            .map_err(|e| Error::Reqwest { e, path: path.clone() })?
+            .bytes().await
+            .map_err(|e| Error::Reqwest { e, path })?
+            .to_vec();
+        let file = content_file_name(&data, filename);
+        fs::write(self.dir.join(&file), &data)?;
+        self.index.insert(url.to_string(), file);
+        self.save_index()?;
+        Ok(data)
+    }
+
+    /// Uploads attachments of the note to Anki and replaces them with references in the fields.
+    /// The note is left untouched if any of the uploads fails.
+    pub async fn store(&mut self, anki: &Anki, note: &mut Note) -> Result<()> {
+        for attachment in note.audio.iter().chain(note.picture.iter()) {
+            self.upload(anki, attachment).await?;
+        }
+        link_attachments(note);
+        Ok(())
+    }
+
+    /// Downloads attachments of the note and replaces them with references in the fields.
+    /// Returns file names with the content to be bundled with the note.
+    pub async fn bundle(&mut self, note: &mut Note) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut files = Vec::new();
+        for attachment in note.audio.iter().chain(note.picture.iter()) {
+            let data = self.fetch(&attachment.url, &attachment.filename).await?;
+            files.push((attachment.filename.clone(), data));
+        }
+        link_attachments(note);
+        Ok(files)
+    }
+
+    async fn upload(&mut self, anki: &Anki, attachment: &Attachment) -> Result<()> {
+        if self.uploaded.contains(&attachment.filename) {
+            return Ok(());
+        }
+        let data = self.fetch(&attachment.url, &attachment.filename).await?;
+        anki.store_media_file(&attachment.filename, &data).await?;
+        self.uploaded.insert(attachment.filename.clone());
+        Ok(())
+    }
+
+    fn save_index(&self) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.index)
+            .map_err(|e| Error::DeserializationError { e, message: "media index".to_string() })?;
+        fs::write(self.dir.join(INDEX_FILE), json)?;
+        Ok(())
+    }
+}
+
+/// Hash of the content with the extension of the original file name
+fn content_file_name(data: &[u8], filename: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(data));
+    match filename.rsplit_once('.') {
+        Some((_, extension)) if !extension.is_empty() => format!("{}.{}", hash, extension),
+        _ => hash,
+    }
+}
+
+/// Replaces attachments of the note with references to the media files in its fields
+fn link_attachments(note: &mut Note) {
+    for attachment in std::mem::take(&mut note.audio) {
+        append(note, &attachment, format!("[sound:{}]", attachment.filename));
+    }
+    for attachment in std::mem::take(&mut note.picture) {
+        append(note, &attachment, format!("<img src=\"{}\">", attachment.filename));
+    }
+}
+
+fn append(note: &mut Note, attachment: &Attachment, reference: String) {
+    for field in attachment.fields.iter() {
+        note.fields.entry(field.clone()).or_default().push_str(&reference);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use super::*;
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("skynki-media-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_content_file_name() {
+        assert_eq!(
+            content_file_name(b"deter", "deter.mp3"),
+            "f46dc62af3171f09b0989daadc18e7b5a38fcdfe48913bb2b5db8db96a1265a1.mp3"
+        );
+        assert_eq!(
+            content_file_name(b"deter", "deter"),
+            "f46dc62af3171f09b0989daadc18e7b5a38fcdfe48913bb2b5db8db96a1265a1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_from_cache() {
+        let dir = cache_dir("fetch");
+        let url = "https://d2fmfepycn0xw0.cloudfront.net?gender=female&accent=american&text=deter";
+        {
+            let mut cache = MediaCache::new(dir.clone()).unwrap();
+            fs::write(dir.join("cached.mp3"), b"sound").unwrap();
+            cache.index.insert(url.to_string(), "cached.mp3".to_string());
+            cache.save_index().unwrap();
+        }
+        // index is read back from the directory, so nothing is downloaded
+        let mut cache = MediaCache::new(dir.clone()).unwrap();
+        assert_eq!(cache.fetch(url, "deter.mp3").await.unwrap(), b"sound".to_vec());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::OnceLock;
+use chrono::Utc;
+use prometheus::{Encoder, Gauge, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+use super::errors::{Error, Result};
+use super::sync::RunReport;
+
+/// Prometheus metrics of the process, served by `GET /metrics` of the HTTP API
+pub struct Metrics {
+    registry: Registry,
+    words_fetched: IntCounter,
+    notes: IntCounterVec,
+    logins: IntCounter,
+    token_refreshes: IntCounter,
+    request_duration: HistogramVec,
+    last_success: Gauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("skynki".to_string()), None)
+            .expect("valid metrics prefix");
+        let metrics = Metrics {
+            words_fetched: IntCounter::new("words_fetched_total", "Words fetched from Skyeng").unwrap(),
+            notes: IntCounterVec::new(Opts::new("notes_total", "Notes by the result of the sync"), &["result"]).unwrap(),
+            logins: IntCounter::new("logins_total", "Logins to Skyeng").unwrap(),
+            token_refreshes: IntCounter::new("token_refreshes_total", "Logins to Skyeng because the token expired").unwrap(),
+            request_duration: HistogramVec::new(
+                prometheus::HistogramOpts::new("request_duration_seconds", "Duration of the requests to Skyeng and AnkiConnect"),
+                &["service", "endpoint"],
+            ).unwrap(),
+            last_success: Gauge::new("last_successful_sync_timestamp_seconds", "Unix time of the end of the last sync without errors").unwrap(),
+            registry,
+        };
+        metrics.registry.register(Box::new(metrics.words_fetched.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.notes.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.logins.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.token_refreshes.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.request_duration.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.last_success.clone())).unwrap();
+        metrics
+    }
+
+    pub fn words_fetched(&self, count: usize) {
+        self.words_fetched.inc_by(count as u64);
+    }
+
+    pub fn notes(&self, report: &RunReport) {
+        self.notes.with_label_values(&["added"]).inc_by(report.added as u64);
+        self.notes.with_label_values(&["updated"]).inc_by(report.updated as u64);
+        self.notes.with_label_values(&["skipped"]).inc_by(report.skipped as u64);
+        self.notes.with_label_values(&["failed"]).inc_by(report.failed as u64);
+    }
+
+    pub fn login(&self) {
+        self.logins.inc();
+    }
+
+    pub fn token_refresh(&self) {
+        self.token_refreshes.inc();
+    }
+
+    /// Observes the duration of the request, once the timer is dropped
+    pub fn request(&self, service: &str, endpoint: &str) -> HistogramTimer {
+        self.request_duration.with_label_values(&[service, endpoint]).start_timer()
+    }
+
+    pub fn sync_succeeded(&self) {
+        self.last_success.set(Utc::now().timestamp() as f64);
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| Error::UnexpectedError(Box::new(e)))?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.words_fetched(3);
+        metrics.notes(&RunReport { added: 2, updated: 0, skipped: 1, failed: 0 });
+        metrics.login();
+        metrics.request("anki", "addNote").observe_duration();
+        metrics.sync_succeeded();
+        let text = metrics.render().unwrap();
+        assert!(text.contains("skynki_words_fetched_total 3"));
+        assert!(text.contains("skynki_notes_total{result=\"added\"} 2"));
+        assert!(text.contains("skynki_notes_total{result=\"skipped\"} 1"));
+        assert!(text.contains("skynki_logins_total 1"));
+        assert!(text.contains("skynki_token_refreshes_total 0"));
+        assert!(text.contains("skynki_request_duration_seconds_count{endpoint=\"addNote\",service=\"anki\"} 1"));
+        assert!(text.contains("skynki_last_successful_sync_timestamp_seconds"));
+    }
+}
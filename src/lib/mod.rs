@@ -2,4 +2,22 @@ pub mod skyeng;
 pub(crate) mod errors;
 pub mod anki;
 pub(crate) mod db_config;
-pub(crate) mod repository;
\ No newline at end of file
+pub(crate) mod repository;
+pub mod template;
+pub mod cloze;
+pub mod media;
+pub mod notes;
+pub mod sync;
+pub mod accounts;
+pub mod config;
+pub mod commands;
+pub mod daemon;
+pub mod http;
+pub mod metrics;
+pub mod apkg;
+pub mod tsv;
+pub mod sink;
+pub mod files;
+pub mod obsidian;
+#[cfg(test)]
+pub(crate) mod testing;
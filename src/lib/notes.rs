@@ -0,0 +1,242 @@
+use std::collections::HashSet;
+use chrono::Utc;
+use super::anki::{Attachment, Note};
+use super::repository::Word;
+use super::skyeng::{Meaning, WordData, WordOfSet, WordSetData};
+use super::template::{DeckOptions, DuplicateOptions, NoteTemplate};
+
+/// Everything needed to turn a meaning into an Anki note
+#[derive(Debug, Clone, Default)]
+pub struct NoteSettings {
+    pub deck: DeckOptions,
+    pub template: NoteTemplate,
+    pub duplicates: DuplicateOptions,
+}
+
+pub struct WordMeaning {
+    pub student_id: u32,
+    pub word: WordOfSet,
+    pub meaning: Meaning,
+}
+
+impl WordMeaning {
+    /// `meaning` keeps the text sent to Anki to detect changes of the meaning in skyeng
+    pub fn to_db_word(&self, target: &str, anki_note_id: Option<u64>, template: &NoteTemplate) -> Word {
+        Word {
+            student_id: self.student_id.into(),
+            wordset_id: self.word.wordset.id.into(),
+            word_id: self.word.word.meaning_id as i64,
+            title: self.word.wordset.title.clone(),
+            subtitle: self.word.wordset.subtitle.clone(),
+            meaning: template.render_text(&self.meaning),
+            created_at: self.word.word.created_at.clone(),
+            exported_at: Some(Utc::now().naive_utc()),
+            anki_note_id: anki_note_id.map(|id| id as i64),
+            target: target.to_string(),
+        }
+    }
+}
+
+pub fn to_word_meanings(student_id: &u32, words: &[WordOfSet], meanings: &[Meaning]) -> Vec<WordMeaning> {
+    let mut result = Vec::new();
+    for word in words {
+        for meaning in meanings {
+            if meaning.id == word.word.meaning_id {
+                result.push(WordMeaning {
+                    student_id: *student_id,
+                    word: word.clone(),
+                    meaning: meaning.clone(),
+                });
+            }
+        }
+    }
+    result
+}
+
+/// Meanings with the word of the first wordset containing them, a meaning of several wordsets is kept once
+pub fn first_wordset_meanings(words: &[WordOfSet], meanings: Vec<Meaning>) -> Vec<(WordOfSet, Meaning)> {
+    let mut seen = HashSet::new();
+    meanings.into_iter()
+        .filter(|m| seen.insert(m.id))
+        .filter_map(|m| words.iter()
+            .find(|w| w.word.meaning_id == m.id)
+            .map(|w| (w.clone(), m)))
+        .collect()
+}
+
+impl From<&Word> for WordOfSet {
+    fn from(word: &Word) -> Self {
+        WordOfSet {
+            wordset: WordSetData {
+                id: word.wordset_id as u32,
+                title: word.title.clone(),
+                subtitle: word.subtitle.clone(),
+            },
+            word: WordData {
+                meaning_id: word.word_id as u64,
+                created_at: word.created_at.clone(),
+            },
+        }
+    }
+}
+
+pub trait ToAttachment {
+    fn to_attachment(&self, fields: Vec<String>) -> Attachment;
+}
+
+impl ToAttachment for String {
+    fn to_attachment(&self, fields: Vec<String>) -> Attachment  {
+        let filename = if self.contains("?") {
+            format!("{}.mp3",self.split("=").last().unwrap())
+        } else {
+            self.split("/").last().unwrap().to_string()
+        };
+
+        Attachment {
+            url: self.clone(),
+            filename,
+            fields
+        }
+    }
+}
+
+pub trait AnkiPersistence {
+    fn to_notes(&self, word: &WordOfSet, settings: &NoteSettings) -> Note;
+}
+
+impl AnkiPersistence for Meaning {
+    fn to_notes(&self, word: &WordOfSet, settings: &NoteSettings) -> Note {
+        let template = &settings.template;
+        let deck_name = settings.deck.deck_name(&word.wordset);
+        let mut audio = vec![
+            self.sound_url.to_attachment(vec![
+                template.audio_field.clone()
+            ])
+        ];
+        if let Some(field) = &template.example_audio_field {
+            audio.extend(self.examples.iter().map(|e| e.sound_url.to_attachment(vec![
+                field.clone()
+            ])));
+        }
+
+        Note {
+            options: settings.duplicates.to_options(&deck_name),
+            deck_name,
+            model_name: template.model_name.clone(),
+            fields: template.render(self),
+            tags: settings.deck.tags(&word.wordset),
+            audio,
+            picture: template.pictures
+                .select(&self.images)
+                .iter()
+                .map(|i| i.url.to_attachment(vec![
+                    template.picture_field.clone()
+                ]))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::lib::skyeng::test::sample_meaning;
+
+    #[test]
+    fn test_url_picture_parse() {
+        let url = "https://cdn-user77752.skyeng.ru/resized-images/200x150/png/50/5a677c4b4a356e7a4a3fc243deb73676.png".to_string();
+        let attachment = url.to_attachment(vec![
+            "Extra".to_string()
+        ]);
+        assert_eq!(attachment.url, url);
+        assert_eq!(attachment.filename, "5a677c4b4a356e7a4a3fc243deb73676.png".to_string());
+        assert_eq!(attachment.fields, vec![
+            "Extra".to_string()
+        ]);
+    }
+
+    #[test]
+    fn test_url_sound_parse() {
+        let url = "https://d2fmfepycn0xw0.cloudfront.net?gender=female&accent=american&text=deter".to_string();
+        let attachment = url.to_attachment(vec![
+            "Extra".to_string()
+        ]);
+        assert_eq!(attachment.url, url);
+        assert_eq!(attachment.filename, "deter.mp3".to_string());
+        assert_eq!(attachment.fields, vec![
+            "Extra".to_string()
+        ]);
+    }
+
+    pub(crate) fn sample_word() -> WordOfSet {
+        WordOfSet {
+            wordset: WordSetData {
+                id: 62494171,
+                title: "Communication".to_string(),
+                subtitle: "Informal communication".to_string(),
+            },
+            word: WordData {
+                meaning_id: 210809,
+                created_at: "2022-02-10T10:40:57+00:00".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_note_pictures() {
+        let meaning = sample_meaning();
+        let note = meaning.to_notes(&sample_word(), &NoteSettings::default());
+        assert_eq!(note.picture.len(), 1);
+        assert_eq!(note.picture[0].filename, "5a677c4b4a356e7a4a3fc243deb73676.png".to_string());
+
+        let mut meaning = meaning;
+        meaning.images.clear();
+        let note = meaning.to_notes(&sample_word(), &NoteSettings::default());
+        assert!(note.picture.is_empty());
+    }
+
+    #[test]
+    fn test_note_wordset_deck() {
+        let settings = NoteSettings {
+            deck: DeckOptions {
+                deck: "Skyeng".to_string(),
+                subdecks: true,
+                wordset_tags: true,
+            },
+            ..NoteSettings::default()
+        };
+        let note = sample_meaning().to_notes(&sample_word(), &settings);
+        assert_eq!(note.deck_name, "Skyeng::Communication::Informal communication");
+        assert_eq!(note.tags, vec!["skyeng", "Communication", "Informal_communication"]);
+        assert_eq!(
+            note.options.duplicate_scope_options.unwrap().deck_name,
+            "Skyeng::Communication::Informal communication"
+        );
+    }
+
+    #[test]
+    fn test_first_wordset_meanings() {
+        let mut other = sample_word();
+        other.wordset.id = 62494172;
+        other.wordset.title = "Work".to_string();
+        let words = vec![sample_word(), other];
+        let pairs = first_wordset_meanings(&words, vec![sample_meaning(), sample_meaning()]);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].0.wordset.title, "Communication");
+        assert_eq!(pairs[0].1.id, 210809);
+    }
+
+    #[test]
+    fn test_stored_word_of_set() {
+        let word = WordMeaning {
+            student_id: 6605911,
+            word: sample_word(),
+            meaning: sample_meaning(),
+        }.to_db_word("Default", Some(1), &NoteTemplate::default());
+        let word_of_set = WordOfSet::from(&word);
+        assert_eq!(word_of_set.wordset.title, "Communication");
+        assert_eq!(word_of_set.word.meaning_id, 210809);
+        assert_eq!(word.anki_note_id, Some(1));
+        assert_eq!(word.target, "Default");
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use async_trait::async_trait;
+use log::warn;
+use super::anki::Attachment;
+use super::errors::Result;
+use super::files::written;
+use super::media::MediaCache;
+use super::notes::ToAttachment;
+use super::sink::{Flashcard, FlashcardSink, Pushed};
+use super::skyeng::WordSetData;
+use super::sync::StopSignal;
+
+/// Marks the part of a note written by skynki, everything around it belongs to the user
+const START: &str = "<!-- skynki:start -->";
+const END: &str = "<!-- skynki:end -->";
+const WORDS_DIR: &str = "Words";
+const WORDSETS_DIR: &str = "Wordsets";
+const ATTACHMENTS_DIR: &str = "Attachments";
+
+/// Writes a note per meaning into `Words/` of an Obsidian vault and an index note per wordset into `Wordsets/`.
+///
+/// Notes are updated in place: only the frontmatter keys of skynki and the block between
+/// the skynki comments are rewritten, keys and sections added by the user are kept.
+/// With the media cache sounds and pictures are copied into `Attachments/` and embedded,
+/// otherwise they are linked by their urls.
+pub struct ObsidianSink {
+    vault: PathBuf,
+    media: Option<MediaCache>,
+    /// wordsets of the pushed cards, their index notes are rewritten by `finish`
+    wordsets: BTreeMap<u32, WordSetData>,
+}
+
+impl ObsidianSink {
+    pub fn new(vault: PathBuf, media: Option<MediaCache>) -> Self {
+        Self {
+            vault,
+            media,
+            wordsets: BTreeMap::new(),
+        }
+    }
+
+    async fn write_meaning(&mut self, card: &Flashcard) -> Result<()> {
+        let meaning = &card.meaning;
+        let dir = self.vault.join(WORDS_DIR);
+        fs::create_dir_all(&dir)?;
+        let path = note_path(&dir, &meaning.text, "meaning_id", meaning.id);
+        let fields = [
+            ("meaning_id", meaning.id.to_string()),
+            ("word", quote(&meaning.text)),
+            ("translation", quote(&meaning.translation.text)),
+            ("transcription", quote(&meaning.transcription)),
+            ("wordset", quote(&card.word.wordset.title)),
+            ("wordset_id", card.word.wordset.id.to_string()),
+            ("created_at", quote(&card.word.word.created_at)),
+        ];
+
+        let mut block = format!("# {}\n\n**{}**", meaning.text, meaning.translation.text);
+        if !meaning.transcription.is_empty() {
+            block.push_str(&format!(" · /{}/", meaning.transcription));
+        }
+        block.push_str("\n\n");
+        block.push_str(&self.embed(&meaning.sound_url.to_attachment(Vec::new()), false).await?);
+        block.push_str("\n\n");
+        if !meaning.definition.text.is_empty() {
+            block.push_str(&format!("> {}\n\n", meaning.definition.text));
+        }
+        for picture in card.note.picture.iter() {
+            block.push_str(&self.embed(picture, true).await?);
+            block.push_str("\n\n");
+        }
+        if !meaning.examples.is_empty() {
+            block.push_str("## Examples\n\n");
+            for example in meaning.examples.iter() {
+                let sound = self.embed(&example.sound_url.to_attachment(Vec::new()), false).await?;
+                block.push_str(&format!("- {} {}\n", example.text.replace(['[', ']'], "**"), sound));
+            }
+            block.push('\n');
+        }
+        let alternatives = meaning.alternatives.iter().flatten().collect::<Vec<_>>();
+        if !alternatives.is_empty() {
+            block.push_str("## Alternatives\n\n");
+            for alternative in alternatives {
+                match &alternative.translation {
+                    Some(translation) => block.push_str(&format!("- {} — {}\n", alternative.text, translation.text)),
+                    None => block.push_str(&format!("- {}\n", alternative.text)),
+                }
+            }
+            block.push('\n');
+        }
+
+        let existing = fs::read_to_string(&path).ok();
+        fs::write(&path, update_note(existing.as_deref(), &fields, &block))?;
+        self.wordsets.insert(card.word.wordset.id, card.word.wordset.clone());
+        Ok(())
+    }
+
+    /// Embedded copy of the attachment in the vault, a link to its url without the media cache
+    /// or if the download fails
+    async fn embed(&mut self, attachment: &Attachment, picture: bool) -> Result<String> {
+        if let Some(cache) = self.media.as_mut() {
+            match cache.fetch(&attachment.url, &attachment.filename).await {
+                Ok(data) => {
+                    let dir = self.vault.join(ATTACHMENTS_DIR);
+                    fs::create_dir_all(&dir)?;
+                    let name = file_name(&attachment.filename);
+                    let path = dir.join(&name);
+                    if !path.exists() {
+                        fs::write(path, data)?;
+                    }
+                    return Ok(format!("![[{}]]", name));
+                }
+                Err(e) => warn!("Failed to fetch {}, the url is linked: {}", attachment.url, e),
+            }
+        }
+        Ok(if picture {
+            format!("![]({})", attachment.url.replace(' ', "%20"))
+        } else {
+            format!("<audio controls src=\"{}\"></audio>", attachment.url.replace('"', "%22"))
+        })
+    }
+
+    /// Lists the word notes of the wordset, read back from their frontmatter
+    fn write_index(&self, wordset: &WordSetData) -> Result<()> {
+        let mut words = Vec::new();
+        for entry in fs::read_dir(self.vault.join(WORDS_DIR))? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "md") {
+                continue;
+            }
+            let text = fs::read_to_string(&path)?;
+            let (frontmatter, _) = split_frontmatter(&text);
+            if value(&frontmatter, "wordset_id") != Some(wordset.id.to_string()) {
+                continue;
+            }
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            let word = value(&frontmatter, "word").unwrap_or_else(|| stem.clone());
+            let translation = value(&frontmatter, "translation").unwrap_or_default();
+            words.push((word, stem, translation));
+        }
+        words.sort();
+
+        let dir = self.vault.join(WORDSETS_DIR);
+        fs::create_dir_all(&dir)?;
+        let path = note_path(&dir, &wordset.title, "wordset_id", wordset.id as u64);
+        let fields = [
+            ("wordset_id", wordset.id.to_string()),
+            ("wordset", quote(&wordset.title)),
+            ("subtitle", quote(&wordset.subtitle)),
+        ];
+        let mut block = format!("# {}\n\n", wordset.title);
+        if !wordset.subtitle.is_empty() {
+            block.push_str(&format!("{}\n\n", wordset.subtitle));
+        }
+        for (word, stem, translation) in words {
+            block.push_str(&format!("- [[{}/{}|{}]] — {}\n", WORDS_DIR, stem, word, translation));
+        }
+        let existing = fs::read_to_string(&path).ok();
+        fs::write(&path, update_note(existing.as_deref(), &fields, &block))?;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl FlashcardSink for ObsidianSink {
+    async fn push(&mut self, cards: Vec<Flashcard>, stop: &StopSignal) -> Result<Vec<Pushed>> {
+        let mut outcomes = Vec::with_capacity(cards.len());
+        for card in cards.iter() {
+            if stop.is_stopped() {
+                outcomes.push(Pushed::Stopped);
+                continue;
+            }
+            self.write_meaning(card).await?;
+            outcomes.push(written(card));
+        }
+        Ok(outcomes)
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        for wordset in std::mem::take(&mut self.wordsets).values() {
+            self.write_index(wordset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Note named after the title, the id is added if a note of another meaning or wordset has the name
+fn note_path(dir: &Path, title: &str, id_key: &str, id: u64) -> PathBuf {
+    let name = file_name(title);
+    let path = dir.join(format!("{}.md", name));
+    let owner = fs::read_to_string(&path).ok()
+        .map(|text| value(&split_frontmatter(&text).0, id_key));
+    match owner {
+        None => path,
+        Some(owner) if owner == Some(id.to_string()) => path,
+        Some(_) => dir.join(format!("{} ({}).md", name, id)),
+    }
+}
+
+/// Name without the characters Obsidian does not allow in file names and links
+fn file_name(title: &str) -> String {
+    let name = title.replace(['/', '\\', ':', '*', '?', '"', '<', '>', '|', '#', '^', '[', ']'], " ");
+    match name.trim() {
+        "" => "untitled".to_string(),
+        name => name.to_string(),
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Value of the frontmatter key, quotes are removed
+fn value(frontmatter: &[&str], key: &str) -> Option<String> {
+    let value = frontmatter.iter()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))?
+        .trim();
+    Some(match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => value.to_string(),
+    })
+}
+
+/// Lines of the frontmatter and the rest of the note
+fn split_frontmatter(text: &str) -> (Vec<&str>, &str) {
+    if let Some(rest) = text.strip_prefix("---\n") {
+        if let Some(body) = rest.strip_prefix("---\n") {
+            return (Vec::new(), body);
+        }
+        if let Some(end) = rest.find("\n---\n") {
+            return (rest[..end].lines().collect(), &rest[end + 5..]);
+        }
+    }
+    (Vec::new(), text)
+}
+
+/// Note with the fields in the frontmatter and the block between the skynki comments.
+/// Other keys of the frontmatter and the text around the block are kept.
+fn update_note(existing: Option<&str>, fields: &[(&str, String)], block: &str) -> String {
+    let (frontmatter, body) = existing.map(split_frontmatter).unwrap_or_default();
+    let mut text = "---\n".to_string();
+    for (key, value) in fields {
+        text.push_str(&format!("{}: {}\n", key, value));
+    }
+    let own = |line: &&str| fields.iter().any(|(key, _)| line.strip_prefix(key).is_some_and(|r| r.starts_with(':')));
+    for line in frontmatter.iter().filter(|l| !own(l)) {
+        text.push_str(line);
+        text.push('\n');
+    }
+    text.push_str("---\n");
+    let managed = format!("{}\n{}{}\n", START, block, END);
+    match (body.find(START), body.find(END)) {
+        (Some(start), Some(end)) if start < end => {
+            let after = &body[end + END.len()..];
+            text.push_str(&body[..start]);
+            text.push_str(&managed);
+            text.push_str(after.strip_prefix('\n').unwrap_or(after));
+        }
+        _ => {
+            text.push_str(&managed);
+            text.push_str(body);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use crate::lib::notes::test::sample_word;
+    use crate::lib::notes::{AnkiPersistence, NoteSettings};
+    use crate::lib::skyeng::test::sample_meaning;
+    use super::*;
+
+    #[test]
+    fn test_update_note() {
+        let fields = [("meaning_id", "1".to_string()), ("word", quote("say \"hi\""))];
+        let note = update_note(None, &fields, "# hi\n");
+        assert_eq!(note, format!("---\nmeaning_id: 1\nword: \"say \\\"hi\\\"\"\n---\n{}\n# hi\n{}\n", START, END));
+        assert_eq!(value(&split_frontmatter(&note).0, "word"), Some("say \"hi\"".to_string()));
+
+        // the user added a key and sections around the block
+        let edited = note.replace("---\n<!--", "aliases:\n  - hello\n---\nMy notes\n<!--") + "\n## Mnemonics\nhigh\n";
+        let updated = update_note(Some(&edited), &[("meaning_id", "1".to_string()), ("word", quote("hi"))], "# hi!\n");
+        assert_eq!(updated, format!(
+            "---\nmeaning_id: 1\nword: \"hi\"\naliases:\n  - hello\n---\nMy notes\n{}\n# hi!\n{}\n\n## Mnemonics\nhigh\n",
+            START, END,
+        ));
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name("deter"), "deter");
+        assert_eq!(file_name("Work/Office: talk?"), "Work Office  talk");
+        assert_eq!(file_name("[]"), "untitled");
+    }
+
+    #[tokio::test]
+    async fn test_obsidian_sink() {
+        let vault = env::temp_dir().join(format!("skynki-vault-{}", std::process::id()));
+        let word = sample_word();
+        let meaning = sample_meaning();
+        let card = Flashcard {
+            note: meaning.to_notes(&word, &NoteSettings::default()),
+            word,
+            meaning,
+            note_id: None,
+        };
+        let mut sink = ObsidianSink::new(vault.clone(), None);
+        sink.push(vec![card.clone()], &StopSignal::default()).await.unwrap();
+        sink.finish().await.unwrap();
+
+        let path = vault.join("Words").join("deter.md");
+        let note = fs::read_to_string(&path).unwrap();
+        assert!(note.starts_with("---\nmeaning_id: 210809\nword: \"deter\"\ntranslation: \"удерживать\"\n"), "{}", note);
+        assert!(note.contains("wordset: \"Communication\"\n"), "{}", note);
+        assert!(note.contains("created_at: \"2022-02-10T10:40:57+00:00\"\n"), "{}", note);
+        assert!(note.contains("**удерживать** · /dɪˈtɜː/"), "{}", note);
+        assert!(note.contains("> To make someone decide not to do something."), "{}", note);
+        assert!(note.contains("- I told him I wasn't interested, but he wasn't **deterred**. <audio controls"), "{}", note);
+        assert!(note.contains("![](https://cdn-user77752.skyeng.ru/"), "{}", note);
+        let index = fs::read_to_string(vault.join("Wordsets").join("Communication.md")).unwrap();
+        assert!(index.contains("subtitle: \"Informal communication\"\n"), "{}", index);
+        assert!(index.contains("- [[Words/deter|deter]] — удерживать\n"), "{}", index);
+
+        // the section of the user survives the next run
+        fs::write(&path, format!("{}\n## My sentences\nNothing deters me.\n", note)).unwrap();
+        let mut sink = ObsidianSink::new(vault.clone(), None);
+        sink.push(vec![card], &StopSignal::default()).await.unwrap();
+        let updated = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(vault).unwrap();
+        assert_eq!(updated, format!("{}\n## My sentences\nNothing deters me.\n", note));
+    }
+}
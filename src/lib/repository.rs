@@ -1,12 +1,17 @@
+use std::collections::HashSet;
 use diesel::pg::PgConnection;
 use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
 use diesel::r2d2::ConnectionManager;
+use diesel::expression::functions::aggregate_ordering::max;
+use diesel::pg::upsert::excluded;
 use crate::Token;
 use crate::schema::{
     token,
-    execution,
+    sync_cursor,
+    sync_run,
     words,
 };
+use serde::Serialize;
 use super::errors::Result;
 
 pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
@@ -50,57 +55,122 @@ impl Into<Token> for DbToken {
 
 impl DbToken {
     fn from(login: &String, token: &Token) -> DbToken {
-        let timestamp_string = token.as_ref().expires.to_string();
-        let secs = timestamp_string[..timestamp_string.len() - 6].parse().unwrap();
-        let microsecs = timestamp_string[timestamp_string.len() - 6..].parse::<u32>().unwrap();
+        // expires is in milliseconds
+        let expires = token.as_ref().expires;
+        let secs = (expires / 1000) as i64;
+        let nanos = (expires % 1000) as u32 * 1_000_000;
         DbToken {
             login: login.clone(),
             value: token.as_ref().value.clone(),
-            expires_at: chrono::NaiveDateTime::from_timestamp_opt(secs, microsecs * 1000).unwrap(),
+            expires_at: chrono::NaiveDateTime::from_timestamp_opt(secs, nanos).unwrap(),
         }
     }
 }
 
-/// Table is used to keep last run time of the script to filter out the words,
-/// which were already processed.
-///
-/// Could be also calculated from words table by finding max `created_at` column.
-#[derive(Insertable, Queryable, AsChangeset, Clone, Debug)]
-#[table_name = "execution"]
-struct LastUpdate{
-    pub last_update: String,
+/// Identifies whose words the cursor tracks and where they are exported to
+#[derive(Clone, Debug, PartialEq)]
+pub struct CursorKey {
+    pub login: String,
+    pub student_id: i64,
+    pub target: String,
 }
 
-impl From<&String> for LastUpdate {
-    fn from(last_update: &String) -> Self {
-        LastUpdate {
-            last_update: last_update.clone(),
+impl CursorKey {
+    pub fn new(login: &str, student_id: u32, target: &str) -> Self {
+        CursorKey {
+            login: login.to_string(),
+            student_id: student_id.into(),
+            target: target.to_string(),
         }
     }
+
+    /// Key of the cursor migrated from the former global `execution` table
+    fn legacy() -> Self {
+        Self::new("", 0, "")
+    }
 }
 
-pub fn get_last_update(pool: &Pool) -> Result<Option<String>> {
-    let connection = pool.get()?;
-    execution::table
-        .select(execution::last_update)
-        .first::<String>(&connection)
+/// Table is used to keep creation time of the last processed word per student and target
+/// to filter out the words, which were already processed.
+///
+/// Could be also calculated from words table by finding max `created_at` column.
+#[derive(Insertable, Queryable, AsChangeset, Clone, Debug)]
+#[table_name = "sync_cursor"]
+struct Cursor {
+    pub login: String,
+    pub student_id: i64,
+    pub target: String,
+    pub last_update: chrono::NaiveDateTime,
+}
+
+fn find_cursor(connection: &PgConnection, key: &CursorKey) -> Result<Option<chrono::NaiveDateTime>> {
+    sync_cursor::table
+        .select(sync_cursor::last_update)
+        .filter(sync_cursor::login.eq(&key.login))
+        .filter(sync_cursor::student_id.eq(key.student_id))
+        .filter(sync_cursor::target.eq(&key.target))
+        .first::<chrono::NaiveDateTime>(connection)
         .optional()
         .map_err(|e| e.into())
 }
 
-pub fn save_last_update(pool: &Pool, last_update: &String) -> Result<()> {
+/// Cursor of the key or the legacy one if the key has no cursor yet
+pub fn get_last_update(pool: &Pool, key: &CursorKey) -> Result<Option<chrono::NaiveDateTime>> {
     let connection = pool.get()?;
-    let update = LastUpdate::from(last_update);
-    diesel::insert_into(execution::table)
-        .values(&update)
-        .on_conflict(execution::last_update)
+    match find_cursor(&connection, key)? {
+        Some(last_update) => Ok(Some(last_update)),
+        None => find_cursor(&connection, &CursorKey::legacy()),
+    }
+}
+
+pub fn save_last_update(pool: &Pool, key: &CursorKey, last_update: &chrono::NaiveDateTime) -> Result<()> {
+    let connection = pool.get()?;
+    let cursor = Cursor {
+        login: key.login.clone(),
+        student_id: key.student_id,
+        target: key.target.clone(),
+        last_update: *last_update,
+    };
+    diesel::insert_into(sync_cursor::table)
+        .values(&cursor)
+        .on_conflict((sync_cursor::login, sync_cursor::student_id, sync_cursor::target))
         .do_update()
-        .set(&update)
+        .set(sync_cursor::last_update.eq(excluded(sync_cursor::last_update)))
+        .execute(&connection)?;
+    Ok(())
+}
+
+/// Counts of a finished sync with the error it ended with
+#[derive(Insertable, Queryable, Clone, Debug, PartialEq)]
+#[table_name = "sync_run"]
+pub struct SyncRun {
+    pub finished_at: chrono::NaiveDateTime,
+    pub added: i64,
+    pub updated: i64,
+    pub skipped: i64,
+    pub failed: i64,
+    pub error: Option<String>,
+}
+
+pub fn save_run(pool: &Pool, run: &SyncRun) -> Result<()> {
+    let connection = pool.get()?;
+    diesel::insert_into(sync_run::table)
+        .values(run)
+        .on_conflict_do_nothing()
         .execute(&connection)?;
     Ok(())
 }
 
-#[derive(Insertable, Queryable, Clone, Debug)]
+pub fn get_last_run(pool: &Pool) -> Result<Option<SyncRun>> {
+    let connection = pool.get()?;
+    sync_run::table
+        .order(sync_run::finished_at.desc())
+        .first::<SyncRun>(&connection)
+        .optional()
+        .map_err(|e| e.into())
+}
+
+#[derive(Insertable, Queryable, Serialize, Clone, Debug, PartialEq)]
 #[table_name="words"]
 pub struct Word {
     pub student_id: i64,
@@ -111,13 +181,204 @@ pub struct Word {
     pub meaning: String,
     pub created_at: String,
     pub exported_at: Option<chrono::NaiveDateTime>,
+    pub anki_note_id: Option<i64>,
+    /// empty for the words exported before targets were introduced
+    pub target: String,
 }
 
+/// Inserts the word or refreshes the exported meaning and note id of an existing one
 pub fn save_word(pool: &Pool, word: &Word) -> Result<()> {
     let connection = pool.get()?;
     diesel::insert_into(words::table)
         .values(word)
-        .on_conflict_do_nothing()
+        .on_conflict((words::student_id, words::target, words::wordset_id, words::word_id))
+        .do_update()
+        .set((
+            words::meaning.eq(excluded(words::meaning)),
+            words::exported_at.eq(excluded(words::exported_at)),
+            words::anki_note_id.eq(excluded(words::anki_note_id)),
+        ))
         .execute(&connection)?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Words of the student already pushed to the sinks of the target,
+/// with the note id if the target has an Anki sink.
+///
+/// Words exported before targets were introduced count for every target,
+/// the same as the legacy cursor does.
+pub fn get_exported_words(pool: &Pool, student_id: i64, target: &str) -> Result<Vec<Word>> {
+    let connection = pool.get()?;
+    let words = words::table
+        .filter(words::student_id.eq(student_id))
+        .filter(words::target.eq_any(vec![target, ""]))
+        .filter(words::exported_at.is_not_null())
+        .order(words::target.desc())
+        .load::<Word>(&connection)?;
+    let mut seen = HashSet::new();
+    Ok(words.into_iter()
+        .filter(|w| seen.insert((w.wordset_id, w.word_id)))
+        .collect())
+}
+
+/// All stored words of the student, the latest first
+pub fn get_words(pool: &Pool, student_id: i64) -> Result<Vec<Word>> {
+    let connection = pool.get()?;
+    words::table
+        .filter(words::student_id.eq(student_id))
+        .order((words::created_at.desc(), words::word_id))
+        .load::<Word>(&connection)
+        .map_err(|e| e.into())
+}
+
+/// Counts of the stored words of a student in a target
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WordStats {
+    pub total: i64,
+    /// words pushed to the sinks
+    pub exported: i64,
+    pub last_exported_at: Option<chrono::NaiveDateTime>,
+}
+
+pub fn get_word_stats(pool: &Pool, student_id: i64, target: &str) -> Result<WordStats> {
+    let connection = pool.get()?;
+    let words = words::table
+        .filter(words::student_id.eq(student_id))
+        .filter(words::target.eq_any(vec![target, ""]));
+    Ok(WordStats {
+        total: words.clone().count().get_result(&connection)?,
+        exported: words.clone().filter(words::exported_at.is_not_null()).count().get_result(&connection)?,
+        last_exported_at: words.select(max(words::exported_at)).first(&connection)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::path::Path;
+    use chrono::NaiveDateTime;
+    use diesel::sql_query;
+    use diesel_migrations::{migration_from, run_migrations};
+    use super::*;
+    use crate::embedded_migrations;
+    use crate::lib::testing::fresh_database;
+
+    fn time(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S").unwrap()
+    }
+
+    fn round_trip(pool: &Pool) {
+        let word = Word {
+            student_id: 6605911,
+            wordset_id: 62494171,
+            word_id: 210809,
+            title: "Communication".to_string(),
+            subtitle: "Informal communication".to_string(),
+            meaning: "{{c1::deter::удерживать}}".to_string(),
+            created_at: "2022-02-10T10:40:57+00:00".to_string(),
+            exported_at: Some(time("2022-02-11T08:00:00")),
+            anki_note_id: Some(1496198395707),
+            target: "Default".to_string(),
+        };
+        save_word(pool, &word).unwrap();
+        assert_eq!(get_exported_words(pool, 6605911, "Default").unwrap(), vec![word.clone()]);
+        assert!(get_exported_words(pool, 6605911, "Kids").unwrap().is_empty());
+        let updated = Word {
+            meaning: "{{c1::deter::сдерживать}}".to_string(),
+            ..word.clone()
+        };
+        save_word(pool, &updated).unwrap();
+        assert_eq!(get_exported_words(pool, 6605911, "Default").unwrap(), vec![updated.clone()]);
+
+        // a word exported before targets belongs to every target, unless the target has its own
+        let legacy = Word {
+            target: "".to_string(),
+            ..word
+        };
+        save_word(pool, &legacy).unwrap();
+        assert_eq!(get_exported_words(pool, 6605911, "Default").unwrap(), vec![updated]);
+        assert_eq!(get_exported_words(pool, 6605911, "Kids").unwrap(), vec![legacy.clone()]);
+
+        // a word written to a file has no note and still counts as exported
+        let written = Word {
+            word_id: 175493,
+            anki_note_id: None,
+            target: "Files".to_string(),
+            ..legacy.clone()
+        };
+        save_word(pool, &written).unwrap();
+        assert_eq!(get_exported_words(pool, 6605911, "Files").unwrap(), vec![written, legacy]);
+
+        let login = "red.avtovo@gmail.com".to_string();
+        assert!(get_token(pool, &login).unwrap().is_none());
+        save_token(pool, &login, &Token::new("jwt".to_string(), 1644763320123)).unwrap();
+        let token = get_token(pool, &login).unwrap().unwrap();
+        assert_eq!(token.value, "jwt");
+        assert_eq!(token.expires, 1644763320123);
+
+        let key = CursorKey::new(&login, 6605911, "Default");
+        let other = CursorKey::new(&login, 6605912, "Default");
+        save_last_update(pool, &key, &time("2022-02-10T10:40:57")).unwrap();
+        save_last_update(pool, &key, &time("2022-02-12T10:40:57")).unwrap();
+        assert_eq!(get_last_update(pool, &key).unwrap(), Some(time("2022-02-12T10:40:57")));
+        assert_ne!(get_last_update(pool, &other).unwrap(), Some(time("2022-02-12T10:40:57")));
+
+        assert_eq!(get_words(pool, 6605911).unwrap().len(), 3);
+
+        let run = SyncRun {
+            finished_at: time("2022-02-12T10:41:00"),
+            added: 2,
+            updated: 0,
+            skipped: 1,
+            failed: 0,
+            error: None,
+        };
+        save_run(pool, &SyncRun { finished_at: time("2022-02-11T10:41:00"), error: Some("failed".to_string()), ..run.clone() }).unwrap();
+        save_run(pool, &run).unwrap();
+        assert_eq!(get_last_run(pool).unwrap(), Some(run));
+        assert_eq!(get_word_stats(pool, 6605911, "Kids").unwrap(), WordStats {
+            total: 1,
+            exported: 1,
+            last_exported_at: Some(time("2022-02-11T08:00:00")),
+        });
+    }
+
+    #[test]
+    #[ignore = "requires Postgres, run `docker compose up -d` in docker/"]
+    fn test_fresh_database() {
+        let pool = fresh_database("skynki_test_fresh");
+        embedded_migrations::run(&pool.get().unwrap()).unwrap();
+        round_trip(&pool);
+        assert_eq!(get_last_update(&pool, &CursorKey::new("someone", 1, "Default")).unwrap(), None);
+    }
+
+    #[test]
+    #[ignore = "requires Postgres, run `docker compose up -d` in docker/"]
+    fn test_upgraded_database() {
+        let pool = fresh_database("skynki_test_upgrade");
+        let connection = pool.get().unwrap();
+        let initial: Vec<_> = ["00000000000000_diesel_initial_setup", "2022-02-13-182044_init"].iter()
+            .map(|name| migration_from(Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations").join(name)).unwrap())
+            .collect();
+        run_migrations(&connection, initial, &mut io::sink()).unwrap();
+        sql_query("INSERT INTO execution VALUES ('2022-02-10T10:40:57+00:00')")
+            .execute(&connection)
+            .unwrap();
+        sql_query("INSERT INTO words VALUES (6605911, 62494171, 'Communication', 'Informal communication', 210809, 'deter', '2022-02-10T10:40:57+00:00', NULL)")
+            .execute(&connection)
+            .unwrap();
+
+        embedded_migrations::run(&connection).unwrap();
+
+        // the global cursor is used until the student gets one of its own
+        let key = CursorKey::new("someone", 1, "Default");
+        assert_eq!(get_last_update(&pool, &key).unwrap(), Some(time("2022-02-10T10:40:57")));
+        let words = words::table.load::<Word>(&connection).unwrap();
+        assert_eq!(words.len(), 1);
+        assert_eq!(words[0].meaning, "deter");
+        assert_eq!(words[0].anki_note_id, None);
+        assert_eq!(words[0].target, "");
+        sql_query("DELETE FROM words").execute(&connection).unwrap();
+        round_trip(&pool);
+    }
+}
@@ -0,0 +1,442 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::Deserialize;
+use super::accounts::Target;
+use super::anki::{Anki, Note};
+use super::errors::{Error, Result};
+use super::files::{CsvSink, JsonSink, MarkdownSink};
+use super::media::MediaCache;
+use super::notes::NoteSettings;
+use super::obsidian::ObsidianSink;
+use super::skyeng::{Meaning, WordOfSet};
+use super::sync::{DryRunFormat, StopSignal};
+use super::template::DuplicateOptions;
+
+/// Note of a meaning on its way to a sink
+#[derive(Debug, Clone)]
+pub struct Flashcard {
+    pub word: WordOfSet,
+    pub meaning: Meaning,
+    pub note: Note,
+    /// id of the note pushed by an earlier run, the card updates it
+    pub note_id: Option<u64>,
+}
+
+/// What a sink did with a single card
+#[derive(Debug)]
+pub enum Pushed {
+    /// new note, with its id if the sink keeps ids
+    Added(Option<u64>),
+    /// the note of the card is updated
+    Updated,
+    /// the sink has such a note already, the card is left out
+    Duplicate,
+    Failed(Error),
+    /// the sync is stopped before the card, it is pushed by the next run
+    Stopped,
+}
+
+/// Destination of the notes built from the words.
+///
+/// A run calls `prepare` with all the new cards, `push` with batches of them and the updated cards,
+/// and `finish` once everything is pushed. An error of `push` fails the whole batch,
+/// a single failed card is reported by its outcome. Sinks spending time on every card
+/// check the stop signal before each of them.
+#[async_trait(?Send)]
+pub trait FlashcardSink {
+    async fn prepare(&mut self, _cards: &[Flashcard]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Outcomes in the order of the cards
+    async fn push(&mut self, cards: Vec<Flashcard>, stop: &StopSignal) -> Result<Vec<Pushed>>;
+
+    async fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Sink of a target, listed under `sinks` of the target or of the config:
+///
+/// ```toml
+/// [[sinks]]
+/// kind = "anki"      # AnkiConnect of the target, the default
+///
+/// [[sinks]]
+/// kind = "csv"       # "json", "markdown" or "obsidian"
+/// path = "skyeng.txt" # the vault folder of "obsidian"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SinkConfig {
+    Anki,
+    /// tab separated file for the text importer of Anki
+    #[serde(alias = "tsv")]
+    Csv { path: PathBuf },
+    /// a JSON object per line
+    Json { path: PathBuf },
+    /// a section per note
+    Markdown { path: PathBuf },
+    /// a note per meaning and an index note per wordset in the folder of a vault
+    Obsidian { path: PathBuf },
+}
+
+impl SinkConfig {
+    /// File or vault folder the sink writes to, AnkiConnect has none
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            SinkConfig::Anki => None,
+            SinkConfig::Csv { path } | SinkConfig::Json { path } | SinkConfig::Markdown { path } | SinkConfig::Obsidian { path } => Some(path),
+        }
+    }
+
+    /// Fails if the path can't be written: a missing path needs a writable parent folder
+    pub fn check_writable(&self) -> std::io::Result<()> {
+        let path = match self.path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let metadata = match fs::metadata(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+                fs::metadata(parent)?
+            }
+            metadata => metadata?,
+        };
+        if metadata.permissions().readonly() {
+            return Err(std::io::Error::new(ErrorKind::PermissionDenied, "read-only"));
+        }
+        Ok(())
+    }
+}
+
+/// Sink of the target, several sinks are fanned out. A dry run prints the notes instead.
+pub fn build_sink(target: &Target, settings: &NoteSettings, media_cache: Option<&Path>, dry_run: Option<DryRunFormat>) -> Result<Box<dyn FlashcardSink>> {
+    if let Some(format) = dry_run {
+        return Ok(Box::new(DryRunSink { format }));
+    }
+    let mut sinks = Vec::<Box<dyn FlashcardSink>>::new();
+    for config in target.sinks.iter() {
+        let media = || media_cache.map(|dir| MediaCache::new(dir.to_path_buf())).transpose();
+        sinks.push(match config {
+            SinkConfig::Anki => {
+                let media = media()?;
+                Box::new(AnkiSink::new(Anki::new(target.anki_url.clone()), settings.duplicates.clone())
+                    .with_profile(target.profile.clone())
+                    .with_media(media))
+            }
+            SinkConfig::Csv { path } => Box::new(CsvSink::new(path.clone(), &settings.template)),
+            SinkConfig::Json { path } => Box::new(JsonSink::new(path.clone())),
+            SinkConfig::Markdown { path } => Box::new(MarkdownSink::new(path.clone())),
+            SinkConfig::Obsidian { path } => Box::new(ObsidianSink::new(path.clone(), media()?)),
+        });
+    }
+    match sinks.len() {
+        1 => Ok(sinks.remove(0)),
+        _ => Ok(Box::new(FanOut::new(sinks))),
+    }
+}
+
+/// Adds the notes with AnkiConnect, skipping duplicates if they are looked for
+pub struct AnkiSink {
+    anki: Anki,
+    profile: Option<String>,
+    duplicates: DuplicateOptions,
+    media: Option<MediaCache>,
+    /// the first field of the model is the one Anki checks for duplicates, known after `prepare`
+    first_field: Option<String>,
+}
+
+impl AnkiSink {
+    pub fn new(anki: Anki, duplicates: DuplicateOptions) -> Self {
+        Self {
+            anki,
+            profile: None,
+            duplicates,
+            media: None,
+            first_field: None,
+        }
+    }
+
+    /// Profile loaded before the notes are added
+    pub fn with_profile(mut self, profile: Option<String>) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn with_media(mut self, media: Option<MediaCache>) -> Self {
+        self.media = media;
+        self
+    }
+
+    async fn create_missing_decks(&self, cards: &[Flashcard]) -> Result<()> {
+        let existing = self.anki.deck_names().await?;
+        let mut missing = cards.iter()
+            .map(|c| &c.note.deck_name)
+            .filter(|d| !existing.contains(d))
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing.dedup();
+        for deck in missing {
+            info!("Creating deck {}", deck);
+            self.anki.create_deck(deck).await?;
+        }
+        Ok(())
+    }
+
+    /// Uploads media of the note through the local cache if it is configured.
+    /// Otherwise or on failure Anki downloads the attachments by itself.
+    async fn store_media(&mut self, note: &mut Note) {
+        if let Some(cache) = self.media.as_mut() {
+            if let Err(e) = cache.store(&self.anki, note).await {
+                warn!("Failed to store media of the note, remote urls are used: {}", e);
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl FlashcardSink for AnkiSink {
+    async fn prepare(&mut self, cards: &[Flashcard]) -> Result<()> {
+        if let Some(profile) = &self.profile {
+            self.anki.load_profile(profile).await?;
+        }
+        if cards.is_empty() {
+            return Ok(());
+        }
+        self.create_missing_decks(cards).await?;
+        if self.duplicates.find_before_add {
+            self.first_field = self.anki.model_field_names(&cards[0].note.model_name)
+                .await?
+                .into_iter()
+                .next();
+        }
+        Ok(())
+    }
+
+    async fn push(&mut self, cards: Vec<Flashcard>, stop: &StopSignal) -> Result<Vec<Pushed>> {
+        let mut outcomes = cards.iter().map(|_| None).collect::<Vec<_>>();
+        let mut added = Vec::new();
+        for (i, card) in cards.into_iter().enumerate() {
+            if stop.is_stopped() {
+                outcomes[i] = Some(Pushed::Stopped);
+                continue;
+            }
+            let mut note = card.note;
+            if let Some(note_id) = card.note_id {
+                self.store_media(&mut note).await;
+                outcomes[i] = Some(match self.anki.update_note_fields(note_id, note).await {
+                    Ok(_) => Pushed::Updated,
+                    Err(e) => Pushed::Failed(e),
+                });
+                continue;
+            }
+            if let Some(query) = self.first_field.as_ref().and_then(|f| self.duplicates.query(&note, f)) {
+                let found = self.anki.find_notes(&query).await?;
+                if !found.is_empty() {
+                    info!("Meaning {} is a duplicate of notes {:?}", card.meaning.id, found);
+                    outcomes[i] = Some(Pushed::Duplicate);
+                    continue;
+                }
+            }
+            self.store_media(&mut note).await;
+            added.push((i, note));
+        }
+        let (indexes, notes): (Vec<_>, Vec<_>) = added.into_iter().unzip();
+        let results = self.anki.add_notes(notes).await?;
+        for (i, result) in indexes.into_iter().zip(results) {
+            outcomes[i] = Some(match result {
+                Ok(note_id) => Pushed::Added(Some(note_id)),
+                Err(e) => Pushed::Failed(e),
+            });
+        }
+        Ok(outcomes.into_iter().flatten().collect())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        // notes are already in the local collection, so AnkiWeb being unavailable is not fatal
+        if let Err(e) = self.anki.sync().await {
+            warn!("Failed to sync anki: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Prints the notes instead of sending them anywhere
+pub struct DryRunSink {
+    format: DryRunFormat,
+}
+
+#[async_trait(?Send)]
+impl FlashcardSink for DryRunSink {
+    async fn push(&mut self, cards: Vec<Flashcard>, _stop: &StopSignal) -> Result<Vec<Pushed>> {
+        let mut outcomes = Vec::with_capacity(cards.len());
+        for card in cards {
+            outcomes.push(match card.note_id {
+                Some(note_id) => {
+                    self.format.print_updated(card.meaning.id, note_id, card.note)?;
+                    Pushed::Updated
+                }
+                None => {
+                    self.format.print_added(card.meaning.id, card.note)?;
+                    Pushed::Added(None)
+                }
+            });
+        }
+        Ok(outcomes)
+    }
+}
+
+/// Pushes every card to all of the sinks.
+///
+/// A card failed or stopped by any of the sinks is failed or stopped, so it is pushed again by the next run,
+/// the sinks which took it already get it once more. The note id comes from the first sink with ids.
+pub struct FanOut {
+    sinks: Vec<Box<dyn FlashcardSink>>,
+}
+
+impl FanOut {
+    pub fn new(sinks: Vec<Box<dyn FlashcardSink>>) -> Self {
+        Self { sinks }
+    }
+}
+
+#[async_trait(?Send)]
+impl FlashcardSink for FanOut {
+    async fn prepare(&mut self, cards: &[Flashcard]) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.prepare(cards).await?;
+        }
+        Ok(())
+    }
+
+    async fn push(&mut self, cards: Vec<Flashcard>, stop: &StopSignal) -> Result<Vec<Pushed>> {
+        let mut combined = cards.iter().map(|_| None).collect::<Vec<Option<Pushed>>>();
+        for sink in self.sinks.iter_mut() {
+            let outcomes = sink.push(cards.clone(), stop).await?;
+            for (result, outcome) in combined.iter_mut().zip(outcomes) {
+                *result = Some(match result.take() {
+                    Some(first) => merge(first, outcome),
+                    None => outcome,
+                });
+            }
+        }
+        Ok(combined.into_iter().flatten().collect())
+    }
+
+    /// Finishes all of the sinks, the first error is returned
+    async fn finish(&mut self) -> Result<()> {
+        let mut first_error = None;
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.finish().await {
+                first_error.get_or_insert(e);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+/// Outcome of a card pushed to two sinks
+fn merge(first: Pushed, other: Pushed) -> Pushed {
+    match (first, other) {
+        (Pushed::Failed(e), _) | (_, Pushed::Failed(e)) => Pushed::Failed(e),
+        (Pushed::Stopped, _) | (_, Pushed::Stopped) => Pushed::Stopped,
+        (Pushed::Added(Some(id)), _) | (_, Pushed::Added(Some(id))) => Pushed::Added(Some(id)),
+        (Pushed::Added(None), _) | (_, Pushed::Added(None)) => Pushed::Added(None),
+        (Pushed::Updated, _) | (_, Pushed::Updated) => Pushed::Updated,
+        (Pushed::Duplicate, Pushed::Duplicate) => Pushed::Duplicate,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use crate::lib::notes::test::sample_word;
+    use crate::lib::notes::AnkiPersistence;
+    use crate::lib::skyeng::test::sample_meaning;
+    use crate::lib::testing::anki::FakeAnki;
+    use super::*;
+
+    fn card(settings: &NoteSettings) -> Flashcard {
+        let word = sample_word();
+        let meaning = sample_meaning();
+        Flashcard {
+            note: meaning.to_notes(&word, settings),
+            word,
+            meaning,
+            note_id: None,
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let failed = || Pushed::Failed(Error::AnkiConnect { message: "failed".to_string() });
+        assert!(matches!(merge(Pushed::Added(Some(1)), failed()), Pushed::Failed(_)));
+        assert!(matches!(merge(Pushed::Stopped, failed()), Pushed::Failed(_)));
+        assert!(matches!(merge(Pushed::Added(Some(1)), Pushed::Stopped), Pushed::Stopped));
+        assert!(matches!(merge(Pushed::Added(None), Pushed::Added(Some(1))), Pushed::Added(Some(1))));
+        assert!(matches!(merge(Pushed::Duplicate, Pushed::Added(None)), Pushed::Added(None)));
+        assert!(matches!(merge(Pushed::Updated, Pushed::Updated), Pushed::Updated));
+        assert!(matches!(merge(Pushed::Duplicate, Pushed::Duplicate), Pushed::Duplicate));
+    }
+
+    #[tokio::test]
+    async fn test_fan_out() {
+        let fake = FakeAnki::start().await;
+        let path = env::temp_dir().join(format!("skynki-fan-out-{}.jsonl", std::process::id()));
+        let target = Target {
+            anki_url: fake.url.clone(),
+            sinks: vec![SinkConfig::Json { path: path.clone() }, SinkConfig::Anki],
+            ..Target::default()
+        };
+        let settings = NoteSettings::default();
+        let mut sink = build_sink(&target, &settings, None, None).unwrap();
+        let cards = vec![card(&settings), card(&settings)];
+        sink.prepare(&cards).await.unwrap();
+        let outcomes = sink.push(cards, &StopSignal::default()).await.unwrap();
+        sink.finish().await.unwrap();
+
+        // the second card is refused by Anki as a duplicate, but it is written to the file
+        assert!(matches!(outcomes[0], Pushed::Added(Some(_))), "{:?}", outcomes);
+        assert!(matches!(outcomes[1], Pushed::Failed(_)), "{:?}", outcomes);
+        assert_eq!(fake.notes().len(), 1);
+        assert_eq!(fake.decks(), vec!["Default".to_string()]);
+        assert_eq!(fake.syncs(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_duplicates_found_before_add() {
+        let fake = FakeAnki::start().await;
+        let settings = NoteSettings {
+            duplicates: DuplicateOptions { find_before_add: true, ..DuplicateOptions::default() },
+            ..NoteSettings::default()
+        };
+        let mut sink = AnkiSink::new(Anki::new(fake.url.clone()), settings.duplicates.clone());
+        let cards = vec![card(&settings)];
+        sink.prepare(&cards).await.unwrap();
+        let outcomes = sink.push(cards.clone(), &StopSignal::default()).await.unwrap();
+        assert!(matches!(outcomes[0], Pushed::Added(Some(_))), "{:?}", outcomes);
+        let outcomes = sink.push(cards, &StopSignal::default()).await.unwrap();
+        assert!(matches!(outcomes[0], Pushed::Duplicate), "{:?}", outcomes);
+    }
+
+    #[tokio::test]
+    async fn test_stopped_before_card() {
+        let fake = FakeAnki::start().await;
+        let settings = NoteSettings::default();
+        let mut sink = AnkiSink::new(Anki::new(fake.url.clone()), settings.duplicates.clone());
+        let cards = vec![card(&settings)];
+        sink.prepare(&cards).await.unwrap();
+        let stop = StopSignal::default();
+        stop.stop();
+        let outcomes = sink.push(cards, &stop).await.unwrap();
+        assert!(matches!(outcomes[0], Pushed::Stopped), "{:?}", outcomes);
+        assert!(fake.notes().is_empty());
+    }
+}
@@ -8,12 +8,15 @@ use scraper::Html;
 use crate::lib::errors::Error::{ServerError, UserError};
 use serde::Deserialize;
 use std::sync::Arc;
+use chrono::{DateTime, NaiveDateTime};
+use async_trait::async_trait;
 
 use super::errors::{
     Error,
     Error::HttpParsingError,
     Result,
 };
+use super::metrics::metrics;
 
 pub(crate) fn curr_millis() -> u128 {
     let now = std::time::SystemTime::now();
@@ -89,6 +92,15 @@ pub struct WordData {
     pub created_at: String,
 }
 
+impl WordData {
+    /// Creation time in UTC, `None` if Skyeng sent it in an unknown format
+    pub fn created(&self) -> Option<NaiveDateTime> {
+        DateTime::parse_from_rfc3339(&self.created_at)
+            .map(|t| t.naive_utc())
+            .ok()
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Words {
     pub meta: Meta,
@@ -121,19 +133,53 @@ impl Debug for Callback{
     }
 }
 
+/// Base urls of the Skyeng services, the real ones by default
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SkyengUrls {
+    /// login, `id.skyeng.ru`
+    pub id: String,
+    /// word sets and words, `api.words.skyeng.ru`
+    pub words: String,
+    /// meanings, `dictionary.skyeng.ru`
+    pub dictionary: String,
+}
+
+impl Default for SkyengUrls {
+    fn default() -> Self {
+        Self {
+            id: "https://id.skyeng.ru".to_string(),
+            words: "https://api.words.skyeng.ru".to_string(),
+            dictionary: "https://dictionary.skyeng.ru".to_string(),
+        }
+    }
+}
+
+/// Where the words of the students come from
+#[async_trait(?Send)]
+pub trait WordsSource {
+    /// Login the words are fetched with, a part of the sync cursor
+    fn user(&self) -> &str;
+    async fn get_word_sets(&mut self, student_id: &u32) -> Result<Vec<WordSetData>>;
+    /// Words of all the word sets of the student
+    async fn get_words(&mut self, student_id: &u32) -> Result<Vec<WordOfSet>>;
+    async fn get_meanings(&mut self, words: &[WordData]) -> Result<Vec<Meaning>>;
+}
+
 #[derive(Debug, Clone)]
 pub struct Skyeng {
     client: Client,
     token: Option<Token>,
     user: String,
     password: String,
+    urls: SkyengUrls,
     token_update_callback: Option<Callback>
 }
 
 impl Skyeng {
     /// Calls login endpoint and gets csrf token + session cookies
     pub(crate) async fn get_csrf(&self) -> Result<String> {
-        let path = "https://id.skyeng.ru/login".to_string();
+        let path = format!("{}/login", self.urls.id);
         let res = self.client.get(&path).send().await.map_err(|e| Error::Reqwest { e, path })?;
 
         let response = res.text().await?;
@@ -155,7 +201,7 @@ impl Skyeng {
     }
 
     async fn get_jwt(&self) -> Result<Token> {
-        let path = "https://id.skyeng.ru/user-api/v1/auth/jwt".to_string();
+        let path = format!("{}/user-api/v1/auth/jwt", self.urls.id);
         let res = self.client.post(&path).send().await.map_err(|e| Error::Reqwest { e, path })?;
         let mut cookies = res.cookies();
         cookies.next()
@@ -170,13 +216,14 @@ impl Skyeng {
 
     pub async fn login(&mut self) -> Result<&mut Self> {
         let csrf = self.get_csrf().await?;
-        let path = "https://id.skyeng.ru/frame/login-submit".to_string();
+        let path = format!("{}/frame/login-submit", self.urls.id);
         let mut params = HashMap::new();
         params.insert("username", &self.user);
         params.insert("password", &self.password);
         params.insert("csrfToken", &csrf);
 
         info!("Logging in user {}", &self.user);
+        metrics().login();
         let rs = self.client.post(&path)
             .form(&params)
             .send().await
@@ -194,8 +241,74 @@ impl Skyeng {
         Ok(self)
     }
 
+    pub fn get_token(&self) -> Option<Token> {
+        match self.token.as_ref() {
+            Some(token) => Some(token.clone()),
+            None => None,
+        }
+    }
+
+    async fn get_fresh_token(&mut self) -> Option<Token> {
+        match if self.token.is_expired() {
+            if self.token.is_some() {
+                metrics().token_refresh();
+            }
+            self.login().await
+        } else {
+            Ok(self)
+        }  {
+            Ok(v) => v.get_token(),
+            _ => None
+        }
+    }
+
+    fn client() -> Client {
+        Client::builder().cookie_store(true).build().unwrap()
+    }
+
+    pub fn new(user: String, password: String) -> Self {
+        Self {
+            client: Self::client(),
+            token: None,
+            user,
+            password,
+            urls: SkyengUrls::default(),
+            token_update_callback: None,
+        }
+    }
+
+    pub fn new_with_token(token: Option<Token>, user: String, password: String) -> Self {
+        Self {
+            client: Self::client(),
+            token,
+            user,
+            password,
+            urls: SkyengUrls::default(),
+            token_update_callback: None,
+        }
+    }
+
+    /// Calls the services at the urls instead of the real ones
+    pub fn with_urls(mut self, urls: SkyengUrls) -> Self {
+        self.urls = urls;
+        self
+    }
+
+    pub fn on_token_update(&mut self, f: impl Fn(&Token) + 'static) {
+        self.token_update_callback = Some(Callback{
+            func: Arc::new(f),
+        });
+    }
+}
+
+#[async_trait(?Send)]
+impl WordsSource for Skyeng {
+    fn user(&self) -> &str {
+        &self.user
+    }
+
     async fn get_word_sets(&mut self, student_id: &u32) -> Result<Vec<WordSetData>> {
-        let path = "https://api.words.skyeng.ru/api/for-vimbox/v1/wordsets.json".to_string();
+        let path = format!("{}/api/for-vimbox/v1/wordsets.json", self.urls.words);
         let page_size = 100;
         let mut current_page = 1;
         let mut word_sets = Vec::new();
@@ -206,6 +319,7 @@ impl Skyeng {
             params.insert("pageSize", &page_size);
             params.insert("studentId", &student_id);
             let token = self.get_fresh_token().await.ok_or_else(|| UserError { message: "Token is not set. Unable to login" })?;
+            let _timer = metrics().request("skyeng", "wordsets.json");
             let res = self.client.get(&path)
                 .query(&params)
                 .bearer_auth(&token.value)
@@ -220,7 +334,7 @@ impl Skyeng {
         Ok(word_sets)
     }
 
-    pub async fn get_words(&mut self, student_id: &u32) -> Result<Vec<WordOfSet>> {
+    async fn get_words(&mut self, student_id: &u32) -> Result<Vec<WordOfSet>> {
         let word_sets = self.get_word_sets(student_id).await?;
         let mut words = Vec::new();
         let page_size = "100".to_string();
@@ -228,10 +342,11 @@ impl Skyeng {
         let curr_time = curr_millis().to_string();
         let token = self.get_fresh_token().await.ok_or_else(|| UserError { message: "Token is not set. Unable to login" })?;
         for word_set in word_sets {
-            let path = format!("https://api.words.skyeng.ru/api/v1/wordsets/{}/words.json", word_set.id);
+            let path = format!("{}/api/v1/wordsets/{}/words.json", self.urls.words, word_set.id);
             let mut current_page = 1;
             loop {
                 info!("Calling {} page {}", path, current_page);
+                let _timer = metrics().request("skyeng", "words.json");
                 let res = self.client.get(&path)
                     .bearer_auth(&token.value)
                     .query(&[
@@ -250,55 +365,13 @@ impl Skyeng {
                 current_page += 1;
             }
         }
+        metrics().words_fetched(words.len());
         Ok(words)
     }
 
-    #[allow(dead_code)]
-    pub fn get_token(&self) -> Option<Token> {
-        match self.token.as_ref() {
-            Some(token) => Some(token.clone()),
-            None => None,
-        }
-    }
-
-    async fn get_fresh_token(&mut self) -> Option<Token> {
-        match if self.token.is_expired() {
-            self.login().await
-        } else {
-            Ok(self)
-        }  {
-            Ok(v) => v.get_token(),
-            _ => None
-        }
-    }
-
-    fn client() -> Client {
-        Client::builder().cookie_store(true).build().unwrap()
-    }
-
-    pub fn new(user: String, password: String) -> Self {
-        Self {
-            client: Self::client(),
-            token: None,
-            user,
-            password,
-            token_update_callback: None,
-        }
-    }
-
-    pub fn new_with_token(token: Option<Token>, user: String, password: String) -> Self {
-        Self {
-            client: Self::client(),
-            token,
-            user,
-            password,
-            token_update_callback: None,
-        }
-    }
-
-    pub async fn get_meanings(&mut self, words: &Vec<WordData>) -> Result<Vec<Meaning>> {
-        let path = "https://dictionary.skyeng.ru/api/for-services/v2/meanings".to_string();
-        let ids = words.into_iter()
+    async fn get_meanings(&mut self, words: &[WordData]) -> Result<Vec<Meaning>> {
+        let path = format!("{}/api/for-services/v2/meanings", self.urls.dictionary);
+        let ids = words.iter()
             .map(|w| w.meaning_id.to_string())
             .collect::<Vec<String>>()
             .join(",");
@@ -306,6 +379,7 @@ impl Skyeng {
             .await
             .ok_or_else(|| UserError { message: "Token is not set. Unable to login" })?;
 
+        let _timer = metrics().request("skyeng", "meanings");
         let res = self.client.get(&path)
             .bearer_auth(&token.value)
             .query(&[("ids", &ids)])
@@ -314,12 +388,6 @@ impl Skyeng {
         serde_json::from_str(&body)
             .map_err(|e| Error::DeserializationError { e, message: body.clone() })
     }
-
-    pub fn on_token_update(&mut self, f: impl Fn(&Token) + 'static) {
-        self.token_update_callback = Some(Callback{
-            func: Arc::new(f),
-        });
-    }
 }
 
 
@@ -362,171 +430,147 @@ pub struct MeaningImage {
 }
 
 pub trait NewWords {
-    fn created_after(self, date_time: &Option<String>) -> Self;
-    fn last_created(&self) -> Option<String>;
+    fn created_after(self, date_time: &Option<NaiveDateTime>) -> Self;
+    fn last_created(&self) -> Option<NaiveDateTime>;
 }
 
 impl NewWords for Vec<WordOfSet> {
-    fn created_after(self, date_time: &Option<String>) -> Self {
-        let date_time_string = date_time.clone().unwrap_or("".to_string());
-        self.iter().filter(|w| w.word.created_at > date_time_string ).cloned().collect()
+    /// Words with unknown creation time are kept to not lose them
+    fn created_after(self, date_time: &Option<NaiveDateTime>) -> Self {
+        match date_time {
+            Some(date_time) => self.into_iter()
+                .filter(|w| w.word.created().map(|c| c > *date_time).unwrap_or(true))
+                .collect(),
+            None => self,
+        }
     }
 
-    fn last_created(&self) -> Option<String> {
+    fn last_created(&self) -> Option<NaiveDateTime> {
         self.iter()
-            .map(|w| &w.word.created_at)
+            .filter_map(|w| w.word.created())
             .max()
-            .map(|w| w.to_string())
     }
 }
 
 #[cfg(test)]
-mod test {
-    use std::env;
+pub(crate) mod test {
+    use std::sync::Mutex;
+    use crate::lib::testing::skyeng::{mock_server, FakeWords, PASSWORD, STUDENT, USER};
     use super::*;
     use test_log::test;
 
-    #[tokio::test]
-    pub async fn test_csrf() {
-        let skyeng = Skyeng::new("".to_string(), "".to_string());
-        let result = skyeng.get_csrf().await;
-        println!("result: {:#?}", result);
-        assert!(result.is_ok());
-        let csrf = result.unwrap();
-        println!("CSRF: {}", csrf);
-        assert!(csrf.len() > 0)
+    /// Meaning of "deter" as returned by the meanings endpoint
+    pub(crate) fn sample_meaning() -> Meaning {
+        serde_json::from_str::<Vec<Meaning>>(include_str!("../../fixtures/skyeng/meanings.json"))
+            .unwrap()
+            .remove(0)
+    }
+
+    fn word_created_at(created_at: &str) -> WordOfSet {
+        WordOfSet {
+            wordset: WordSetData { id: 1, title: "".to_string(), subtitle: "".to_string() },
+            word: WordData { meaning_id: 1, created_at: created_at.to_string() },
+        }
+    }
+
+    #[test]
+    fn test_created_after() {
+        let words = vec![
+            word_created_at("2022-02-10T10:40:57+00:00"),
+            word_created_at("2022-02-10T12:40:57+03:00"),
+            word_created_at("unknown"),
+        ];
+        let cursor = NaiveDateTime::parse_from_str("2022-02-10T09:40:57", "%Y-%m-%dT%H:%M:%S").unwrap();
+        let new_words = words.clone().created_after(&Some(cursor));
+        assert_eq!(new_words.len(), 2);
+        assert_eq!(new_words[0].word.created_at, "2022-02-10T10:40:57+00:00");
+        assert_eq!(words.clone().created_after(&None).len(), 3);
+        assert_eq!(
+            words.last_created(),
+            Some(NaiveDateTime::parse_from_str("2022-02-10T10:40:57", "%Y-%m-%dT%H:%M:%S").unwrap())
+        );
     }
 
     async fn skyeng() -> Skyeng {
-        Skyeng::new_with_token(
-            None,
-            "red.avtovo@gmail.com".to_string(),
-            env::var("PASS").expect("User password expected"),
-        ).login().await.unwrap().clone()
+        let urls = mock_server().await;
+        Skyeng::new(USER.to_string(), PASSWORD.to_string())
+            .with_urls(urls)
     }
 
     #[tokio::test]
-    // Token {
-    //   value: "some token",
-    //   expires: 1644763320000,
-    // }
-    pub async fn test_login() {
-        let mut skyeng = Skyeng::new(
-            "red.avtovo@gmail.com".to_string(),
-            env::var("PASS").expect("User password expected"),
-        );
-        let result = skyeng.login().await;
-        assert!(result.is_ok());
-        let option = &result.unwrap().token;
-        println!("result: {:#?}", option);
-        assert!(option.is_some());
-        let token = option.as_ref().unwrap();
-        assert!(token.value.len() > 0);
-    }
-
-    //[
-    //WordSetData {
-    //             id: 62494171,
-    //             title: "Communication",
-    //             subtitle: "Informal communication",
-    //         },
-    //]
-    #[test(tokio::test)]
-    pub async fn test_get_word_sets() {
-        let mut skyeng = skyeng().await;
-        let result = skyeng.get_word_sets(&6605911).await;
-        println!("result: {:#?}", result);
-        assert!(result.is_ok());
+    async fn test_csrf() {
+        let csrf = skyeng().await.get_csrf().await.unwrap();
+        assert_eq!(csrf, "2f6c1a9e8b7d4c3a");
     }
 
-    //[
-    // WordData {
-    //         meaning_id: 175493,
-    //         created_at: "2022-02-10T10:40:57+00:00",
-    //     },
-    //]
     #[tokio::test]
-    pub async fn test_get_words() {
+    async fn test_login() {
         let mut skyeng = skyeng().await;
-        let result = skyeng.get_words(&6605911).await;
-        assert!(result.is_ok());
-        println!("result: {}", result.unwrap().len());
+        let updated = Arc::new(Mutex::new(None));
+        let callback = updated.clone();
+        skyeng.on_token_update(move |token| *callback.lock().unwrap() = Some(token.value.clone()));
+        skyeng.login().await.unwrap();
+        let token = skyeng.get_token().unwrap();
+        assert_eq!(token.value, "mock-token");
+        assert!(!token.is_expired());
+        assert_eq!(*updated.lock().unwrap(), Some("mock-token".to_string()));
+
+        let mut skyeng = Skyeng::new(USER.to_string(), "wrong".to_string())
+            .with_urls(mock_server().await);
+        assert!(matches!(skyeng.login().await, Err(UserError { .. })));
+    }
+
+    #[test(tokio::test)]
+    async fn test_get_word_sets() {
+        let word_sets = skyeng().await.get_word_sets(&STUDENT).await.unwrap();
+        let ids = word_sets.iter().map(|s| s.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![62494171, 62494172]);
+        assert_eq!(word_sets[0].title, "Communication");
     }
 
-    //[
-    // WordData {
-    //         meaning_id: 175493,
-    //         created_at: "2022-02-10T10:40:57+00:00",
-    //     },
-    //]
     #[tokio::test]
-    pub async fn test_get_new_words() {
-        let mut skyeng = skyeng().await;
-        let result = skyeng.get_words(&6605911).await.map(
-            |words| words.created_after(
-                &Some("2022-01-01T00:00:00.000Z".to_string())
-            )
-        );
-        assert!(result.is_ok());
-        let vec = result.unwrap();
-        println!("result: {:#?}", vec);
-        println!("result: {}", vec.len());
+    async fn test_get_words() {
+        let words = skyeng().await.get_words(&STUDENT).await.unwrap();
+        assert_eq!(words.len(), 2);
+        assert_eq!(words[0].word.meaning_id, 210809);
+        assert_eq!(words[0].wordset.title, "Communication");
+        assert_eq!(words[1].word.meaning_id, 175493);
+        assert_eq!(words[1].wordset.title, "Work");
     }
 
     #[tokio::test]
-    pub async fn test_get_last_created_words() {
-        let mut skyeng = skyeng().await;
-        let result = skyeng.get_words(&6605911).await
-            .unwrap()
-            .last_created();
-        assert!(result.is_some());
-        println!("result: {}", result.unwrap());
-    }
-
-    //[
-    //Meaning {
-    //             alternatives: None,
-    //             definition: MeaningDefinition {
-    //                 text: "To make someone decide not to do something.",
-    //                 sound_url: "https://d2fmfepycn0xw0.cloudfront.net?gender=female&accent=american&text=to+make+someone+decide+not+to+do+something",
-    //             },
-    //             examples: [
-    //                 MeaningDefinition {
-    //                     text: "I told him I wasn\'t interested, but he wasn\'t [deterred].",
-    //                     sound_url: "https://d2fmfepycn0xw0.cloudfront.net?gender=female&accent=american&text=I+told+him+I+wasn%27t+interested+but+he+wasn%27t+deterred.",
-    //                 },
-    //                 MeaningDefinition {
-    //                     text: "The rain didn’t [deter] people from coming to the game.",
-    //                     sound_url: "https://d2fmfepycn0xw0.cloudfront.net?gender=female&accent=american&text=The+rain+didn%E2%80%99t+deter+people+from+coming+to+the+game.",
-    //                 },
-    //             ],
-    //             id: 210809,
-    //             images: [
-    //                 MeaningImage {
-    //                     url: "https://cdn-user77752.skyeng.ru/resized-images/200x150/png/50/5a677c4b4a356e7a4a3fc243deb73676.png",
-    //                 },
-    //             ],
-    //             sound_url: "https://d2fmfepycn0xw0.cloudfront.net?gender=female&accent=american&text=deter",
-    //             text: "deter",
-    //             transcription: "dɪˈtɜː",
-    //             translation: Translation {
-    //                 text: "удерживать",
-    //             },
-    //         },
-    //]
+    async fn test_get_new_words() {
+        let words = skyeng().await.get_words(&STUDENT).await.unwrap()
+            .created_after(&Some(NaiveDateTime::parse_from_str("2022-02-11T00:00:00", "%Y-%m-%dT%H:%M:%S").unwrap()));
+        assert_eq!(words.len(), 1);
+        assert_eq!(words[0].word.meaning_id, 175493);
+    }
+
     #[tokio::test]
-    pub async fn test_get_meanings() {
+    async fn test_get_last_created_words() {
+        let last = skyeng().await.get_words(&STUDENT).await.unwrap().last_created();
+        assert_eq!(last, Some(NaiveDateTime::parse_from_str("2022-02-12T08:00:00", "%Y-%m-%dT%H:%M:%S").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_get_meanings() {
         let mut skyeng = skyeng().await;
-        let words = skyeng.get_words(&6605911).await
-            .unwrap()
-            .iter().rev()
-            .take(2)
-            .map(|v| v.clone())
-            .collect::<Vec<WordOfSet>>();
-        let data = words.iter().map(|v| v.word.clone()).collect::<Vec<WordData>>();
-        let result = skyeng.get_meanings(&data).await;
-        println!("result: {:#?}", result);
-        assert!(result.is_ok());
-        assert!(result.unwrap().len() >= 2);
-    }
-}
\ No newline at end of file
+        let words = skyeng.get_words(&STUDENT).await.unwrap();
+        let data = words.iter().map(|w| w.word.clone()).collect::<Vec<_>>();
+        let meanings = skyeng.get_meanings(&data).await.unwrap();
+        let texts = meanings.iter().map(|m| m.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["deter", "chat"]);
+        assert_eq!(meanings[0].translation.text, "удерживать");
+        assert!(skyeng.get_meanings(&data[1..]).await.unwrap().iter().all(|m| m.id == 175493));
+    }
+
+    #[tokio::test]
+    async fn test_fake_words() {
+        let mut fake = FakeWords::from_fixtures();
+        let words = fake.get_words(&STUDENT).await.unwrap();
+        assert_eq!(words.len(), 2);
+        let meanings = fake.get_meanings(&[words[0].word.clone()]).await.unwrap();
+        assert_eq!(meanings.len(), 1);
+        assert_eq!(meanings[0].text, "deter");
+    }
+}
@@ -0,0 +1,612 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::AddAssign;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use chrono::{NaiveDateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use tokio::sync::Notify;
+use super::accounts::{Account, SkyengClients, Target};
+use super::anki::{add_note_request, update_note_fields_request, Fields, Note};
+use super::errors::{Error, Result};
+use super::metrics::metrics;
+use super::notes::{first_wordset_meanings, to_word_meanings, AnkiPersistence, NoteSettings};
+use super::repository::{get_exported_words, get_last_update, save_last_update, save_run, save_word, CursorKey, Pool, SyncRun};
+use super::sink::{build_sink, Flashcard, FlashcardSink, Pushed};
+use super::skyeng::{Meaning, NewWords, WordData, WordOfSet, WordsSource};
+
+/// Amount of notes pushed to Anki before the progress is persisted
+const PUSH_BATCH_SIZE: usize = 100;
+
+/// Amount of meaning ids sent to Skyeng in a single request
+const MEANINGS_CHUNK_SIZE: usize = 100;
+
+/// Counts of the notes processed by a single run
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RunReport {
+    pub added: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "added: {}, updated: {}, skipped: {}, failed: {}", self.added, self.updated, self.skipped, self.failed)
+    }
+}
+
+impl RunReport {
+    /// Finished run to be stored with the error it ended with
+    fn to_run(&self, error: Option<&Error>) -> SyncRun {
+        SyncRun {
+            finished_at: Utc::now().naive_utc(),
+            added: self.added as i64,
+            updated: self.updated as i64,
+            skipped: self.skipped as i64,
+            failed: self.failed as i64,
+            error: error.map(|e| e.to_string()),
+        }
+    }
+}
+
+impl AddAssign for RunReport {
+    fn add_assign(&mut self, other: Self) {
+        self.added += other.added;
+        self.updated += other.updated;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+    }
+}
+
+/// How a dry run prints the notes instead of sending them to Anki
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum DryRunFormat {
+    /// a line per note with its deck, model and shortened fields
+    #[default]
+    Table,
+    /// a line per AnkiConnect request
+    Json,
+}
+
+impl DryRunFormat {
+    pub(crate) fn print_added(&self, meaning_id: u64, note: Note) -> Result<()> {
+        match self {
+            DryRunFormat::Table => println!(
+                "add\t{}\t{}\t{}\t{}",
+                meaning_id, note.deck_name, note.model_name, summary(&note.fields)
+            ),
+            DryRunFormat::Json => println!("{}", add_note_request(note)?),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn print_updated(&self, meaning_id: u64, note_id: u64, note: Note) -> Result<()> {
+        match self {
+            DryRunFormat::Table => println!("update\t{}\tnote {}\t{}", meaning_id, note_id, summary(&note.fields)),
+            DryRunFormat::Json => println!("{}", update_note_fields_request(note_id, note)?),
+        }
+        Ok(())
+    }
+}
+
+/// Fields of a note on a single line, long values are cut
+fn summary(fields: &Fields) -> String {
+    const MAX_CHARS: usize = 40;
+    fields.iter()
+        .map(|(name, value)| {
+            let value = value.replace('\n', " ");
+            if value.chars().count() > MAX_CHARS {
+                format!("{}: {}...", name, value.chars().take(MAX_CHARS).collect::<String>())
+            } else {
+                format!("{}: {}", name, value)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+/// Asks a running sync to stop once the current note is done
+#[derive(Debug, Clone, Default)]
+pub struct StopSignal {
+    stopped: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl StopSignal {
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the stop is requested
+    pub async fn stopped(&self) {
+        loop {
+            // created before the check to not miss a stop in between
+            let notified = self.notify.notified();
+            if self.is_stopped() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// What a sync does besides adding new notes
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// updates notes of the already exported words, which meaning was changed
+    pub resync: bool,
+    /// prints the notes instead of sending them to the sinks, nothing is stored either
+    pub dry_run: Option<DryRunFormat>,
+    pub stop: StopSignal,
+}
+
+/// Moves new words of a student from Skyeng to the sink of a target:
+/// fetch words -> fetch meanings -> build notes -> push -> persist.
+///
+/// Every pushed batch is persisted right away, so a failure in the middle of a run
+/// keeps the sink and the database in step and the next run continues from there.
+pub struct SyncPipeline {
+    pool: Pool,
+    sink: Box<dyn FlashcardSink>,
+    settings: NoteSettings,
+    options: SyncOptions,
+    /// name of the target in the sync cursor and the stored words
+    target: String,
+    /// meanings left behind because of the stop signal, the cursor stays before them
+    interrupted: HashSet<u64>,
+}
+
+impl SyncPipeline {
+    pub fn new(pool: Pool, sink: Box<dyn FlashcardSink>, settings: NoteSettings, options: SyncOptions, target: String) -> Self {
+        Self {
+            pool,
+            sink,
+            settings,
+            options,
+            target,
+            interrupted: HashSet::new(),
+        }
+    }
+
+    pub async fn run(&mut self, skyeng: &mut dyn WordsSource, student: u32) -> Result<RunReport> {
+        let mut report = RunReport::default();
+        self.interrupted.clear();
+        let key = CursorKey::new(skyeng.user(), student, &self.target);
+        let words = self.fetch_words(skyeng, &key, student).await?;
+        let meanings = self.fetch_meanings(skyeng, &words).await?;
+        let cards = self.build_cards(student, meanings, &mut report)?;
+        self.push(&key, student, &words, cards, &mut report).await?;
+        if self.options.stop.is_stopped() {
+            info!("Sync of student {} to {} stopped. {}", student, self.target, report);
+            return Ok(report);
+        }
+        if self.options.resync {
+            self.resync(skyeng, student, &mut report).await?;
+        }
+        self.sink.finish().await?;
+        if self.options.dry_run.is_some() {
+            info!("Dry run of student {} to {} finished. {}", student, self.target, report);
+        } else {
+            info!("Sync of student {} to {} finished. {}", student, self.target, report);
+        }
+        Ok(report)
+    }
+
+    async fn fetch_words(&self, skyeng: &mut dyn WordsSource, key: &CursorKey, student: u32) -> Result<Vec<WordOfSet>> {
+        let last_update = get_last_update(&self.pool, key)?;
+        let mut words = skyeng
+            .get_words(&student)
+            .await?
+            .created_after(&last_update);
+        words.sort_by_key(|w| w.word.created());
+        Ok(words)
+    }
+
+    /// Meanings with the word of the first wordset containing them
+    async fn fetch_meanings(&self, skyeng: &mut dyn WordsSource, words: &[WordOfSet]) -> Result<Vec<(WordOfSet, Meaning)>> {
+        if words.is_empty() {
+            return Ok(Vec::new());
+        }
+        let data = words.iter().map(|w| w.word.clone()).collect::<Vec<_>>();
+        let meanings = get_meanings(skyeng, &data).await?;
+        Ok(first_wordset_meanings(words, meanings))
+    }
+
+    /// Builds notes of the meanings, skipping already exported ones
+    fn build_cards(&mut self, student: u32, meanings: Vec<(WordOfSet, Meaning)>, report: &mut RunReport) -> Result<Vec<Flashcard>> {
+        let exported = get_exported_words(&self.pool, student.into(), &self.target)?
+            .into_iter()
+            .map(|w| w.word_id as u64)
+            .collect::<HashSet<_>>();
+        let mut cards = Vec::new();
+        for (word, meaning) in meanings {
+            if self.options.stop.is_stopped() {
+                self.interrupted.insert(meaning.id);
+                continue;
+            }
+            if exported.contains(&meaning.id) {
+                info!("Meaning {} skipped as it is already exported", meaning.id);
+                report.skipped += 1;
+                continue;
+            }
+            let note = meaning.to_notes(&word, &self.settings);
+            cards.push(Flashcard { word, meaning, note, note_id: None });
+        }
+        Ok(cards)
+    }
+
+    async fn push(&mut self, key: &CursorKey, student: u32, words: &[WordOfSet], cards: Vec<Flashcard>, report: &mut RunReport) -> Result<()> {
+        self.sink.prepare(&cards).await?;
+        let mut failed = HashSet::new();
+        let mut cards = cards;
+        while !cards.is_empty() {
+            if self.options.stop.is_stopped() {
+                self.interrupted.extend(cards.iter().map(|c| c.meaning.id));
+                break;
+            }
+            let rest = cards.split_off(PUSH_BATCH_SIZE.min(cards.len()));
+            let batch = std::mem::replace(&mut cards, rest);
+            let meanings = batch.iter().map(|c| c.meaning.clone()).collect::<Vec<_>>();
+            let outcomes = self.sink.push(batch, &self.options.stop).await?;
+            for (meaning, outcome) in meanings.iter().zip(outcomes) {
+                match outcome {
+                    Pushed::Added(note_id) => {
+                        match note_id {
+                            Some(note_id) => info!("Meaning {} exported as note {}", meaning.id, note_id),
+                            None => info!("Meaning {} exported", meaning.id),
+                        }
+                        self.persist(student, words, meaning, note_id)?;
+                        report.added += 1;
+                    }
+                    Pushed::Updated => report.updated += 1,
+                    Pushed::Duplicate => {
+                        info!("Meaning {} skipped as a duplicate", meaning.id);
+                        report.skipped += 1;
+                        // stored without a note to not process it again
+                        self.persist(student, words, meaning, None)?;
+                    }
+                    Pushed::Failed(e) => {
+                        error!("Failed to export meaning {}: {}", meaning.id, e);
+                        failed.insert(meaning.id);
+                        report.failed += 1;
+                    }
+                    Pushed::Stopped => {
+                        self.interrupted.insert(meaning.id);
+                    }
+                }
+            }
+        }
+
+        if self.options.dry_run.is_some() {
+            return Ok(());
+        }
+        failed.extend(self.interrupted.iter().copied());
+        if let Some(cursor) = cursor(words, &failed) {
+            save_last_update(&self.pool, key, &cursor)?;
+        }
+        Ok(())
+    }
+
+    /// Updates notes of already exported words, which meaning was changed in skyeng
+    async fn resync(&mut self, skyeng: &mut dyn WordsSource, student: u32, report: &mut RunReport) -> Result<()> {
+        // only the words with a note can be updated
+        let stored = get_exported_words(&self.pool, student.into(), &self.target)?
+            .into_iter()
+            .filter_map(|w| w.anki_note_id.map(|id| (id as u64, w)))
+            .collect::<Vec<_>>();
+        if stored.is_empty() {
+            return Ok(());
+        }
+        let data = stored.iter()
+            .map(|(_, w)| WordData { meaning_id: w.word_id as u64, created_at: w.created_at.clone() })
+            .collect::<Vec<_>>();
+        let fresh = get_meanings(skyeng, &data).await?;
+        for (note_id, word) in stored {
+            if self.options.stop.is_stopped() {
+                break;
+            }
+            let meaning = match fresh.iter().find(|m| m.id == word.word_id as u64) {
+                Some(meaning) => meaning,
+                None => continue,
+            };
+            let text = self.settings.template.render_text(meaning);
+            if text == word.meaning {
+                continue;
+            }
+            let word = WordOfSet::from(&word);
+            let card = Flashcard {
+                note: meaning.to_notes(&word, &self.settings),
+                word: word.clone(),
+                meaning: meaning.clone(),
+                note_id: Some(note_id),
+            };
+            for outcome in self.sink.push(vec![card], &self.options.stop).await? {
+                match outcome {
+                    Pushed::Stopped => {}
+                    Pushed::Failed(e) => {
+                        error!("Failed to update note {} of meaning {}: {}", note_id, meaning.id, e);
+                        report.failed += 1;
+                    }
+                    _ => {
+                        info!("Note {} of meaning {} updated", note_id, meaning.id);
+                        self.persist(student, std::slice::from_ref(&word), meaning, Some(note_id))?;
+                        report.updated += 1;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores all words of the meaning, nothing is stored by a dry run
+    fn persist(&self, student: u32, words: &[WordOfSet], meaning: &Meaning, note_id: Option<u64>) -> Result<()> {
+        if self.options.dry_run.is_some() {
+            return Ok(());
+        }
+        for word in to_word_meanings(&student, words, std::slice::from_ref(meaning)) {
+            save_word(&self.pool, &word.to_db_word(&self.target, note_id, &self.settings.template))?;
+        }
+        Ok(())
+    }
+}
+
+/// Meanings of the words, requested in chunks to keep the urls short
+pub(crate) async fn get_meanings(skyeng: &mut dyn WordsSource, words: &[WordData]) -> Result<Vec<Meaning>> {
+    let mut meanings = Vec::new();
+    for chunk in words.chunks(MEANINGS_CHUNK_SIZE) {
+        meanings.extend(skyeng.get_meanings(chunk).await?);
+    }
+    Ok(meanings)
+}
+
+/// Syncs every student of the accounts to all of its targets.
+///
+/// A failed account or target does not stop the others, the first error is returned
+/// once all of them are processed. The stop signal ends the sync after the current note.
+pub async fn sync_accounts(pool: &Pool, accounts: &[Account], clients: &mut SkyengClients, settings: &NoteSettings, media_cache: Option<&Path>, options: &SyncOptions) -> Result<RunReport> {
+    let mut report = RunReport::default();
+    let mut first_error = None;
+    for account in accounts {
+        let skyeng = match clients.get(pool, account) {
+            Ok(skyeng) => skyeng,
+            Err(e) => {
+                error!("Failed to prepare account {}: {}", account.username, e);
+                first_error.get_or_insert(e);
+                continue;
+            }
+        };
+        for student in account.students.iter() {
+            for target in student.targets.iter() {
+                if options.stop.is_stopped() {
+                    break;
+                }
+                match sync_target(pool, skyeng, student.id, target, settings, media_cache, options.clone()).await {
+                    Ok(target_report) => {
+                        if options.dry_run.is_none() {
+                            metrics().notes(&target_report);
+                        }
+                        report += target_report;
+                    }
+                    Err(e) => {
+                        error!("Failed to sync student {} of {}: {}", student.id, account.username, e);
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+        }
+    }
+    if options.dry_run.is_none() {
+        // the sync is done either way, a failure to store its counts only shows up in the log
+        if let Err(e) = save_run(pool, &report.to_run(first_error.as_ref())) {
+            error!("Failed to store the sync run: {}", e);
+        }
+    }
+    match first_error {
+        Some(e) => {
+            info!("Sync finished with errors. {}", report);
+            Err(e)
+        }
+        None => {
+            if options.dry_run.is_none() {
+                metrics().sync_succeeded();
+            }
+            Ok(report)
+        }
+    }
+}
+
+async fn sync_target(pool: &Pool, skyeng: &mut dyn WordsSource, student: u32, target: &Target, settings: &NoteSettings, media_cache: Option<&Path>, options: SyncOptions) -> Result<RunReport> {
+    let mut settings = settings.clone();
+    if let Some(deck) = &target.deck {
+        settings.deck.deck = deck.clone();
+    }
+    let sink = build_sink(target, &settings, media_cache, options.dry_run)?;
+    let name = target.name(&settings.deck.deck);
+    let mut pipeline = SyncPipeline::new(pool.clone(), sink, settings, options, name);
+    pipeline.run(skyeng, student).await
+}
+
+/// Creation time of the last word, which does not need to be processed again.
+///
+/// Words are processed in the order of creation, so the cursor stops
+/// right before the first failed word to retry it with the next run.
+fn cursor(words: &[WordOfSet], failed: &HashSet<u64>) -> Option<NaiveDateTime> {
+    let first_failed = match words.iter()
+        .filter(|w| failed.contains(&w.word.meaning_id))
+        .filter_map(|w| w.word.created())
+        .min() {
+        Some(created) => created,
+        None => return words.to_vec().last_created(),
+    };
+    words.iter()
+        .filter_map(|w| w.word.created())
+        .filter(|c| *c < first_failed)
+        .max()
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::DateTime;
+    use super::*;
+    use crate::lib::notes::test::sample_word;
+    use crate::lib::anki::Anki;
+    use crate::lib::sink::AnkiSink;
+    use crate::lib::template::{DeckOptions, DuplicateOptions};
+    use crate::embedded_migrations;
+    use crate::lib::repository::get_words;
+    use crate::lib::testing::anki::FakeAnki;
+    use crate::lib::files::JsonSink;
+    use crate::lib::testing::skyeng::{FakeWords, STUDENT, USER};
+    use crate::lib::testing::{fresh_database, unused_pool};
+
+    fn word(meaning_id: u64, created_at: &str) -> WordOfSet {
+        let mut word = sample_word();
+        word.word.meaning_id = meaning_id;
+        word.word.created_at = created_at.to_string();
+        word
+    }
+
+    fn time(value: &str) -> Option<NaiveDateTime> {
+        Some(NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S").unwrap())
+    }
+
+    #[test]
+    fn test_cursor() {
+        let words = vec![
+            word(1, "2022-02-10T10:40:57+00:00"),
+            word(2, "2022-02-11T10:40:57+00:00"),
+            word(3, "2022-02-12T10:40:57+00:00"),
+        ];
+        assert_eq!(cursor(&words, &HashSet::new()), time("2022-02-12T10:40:57"));
+        assert_eq!(cursor(&words, &[3].iter().copied().collect()), time("2022-02-11T10:40:57"));
+        assert_eq!(cursor(&words, &[2, 3].iter().copied().collect()), time("2022-02-10T10:40:57"));
+        assert_eq!(cursor(&words, &[1].iter().copied().collect()), None);
+        assert_eq!(cursor(&[], &HashSet::new()), None);
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut fields = Fields::new();
+        fields.insert("Back".to_string(), "удерживать\nсдерживать".to_string());
+        fields.insert("Front".to_string(), "I told him I wasn't interested, but he wasn't deterred.".to_string());
+        assert_eq!(
+            summary(&fields),
+            "Back: удерживать сдерживать | Front: I told him I wasn't interested, but he w..."
+        );
+    }
+
+    #[test]
+    fn test_report_display() {
+        let report = RunReport { added: 3, updated: 1, skipped: 2, failed: 0 };
+        assert_eq!(report.to_string(), "added: 3, updated: 1, skipped: 2, failed: 0");
+
+        let mut total = report.clone();
+        total += RunReport { added: 1, updated: 0, skipped: 0, failed: 2 };
+        assert_eq!(total, RunReport { added: 4, updated: 1, skipped: 2, failed: 2 });
+    }
+
+    #[tokio::test]
+    async fn test_fetch_meanings() {
+        let mut fake = FakeWords::from_fixtures();
+        // a meaning missing in the dictionary is left out
+        fake.words.push(word(1, "2022-02-13T00:00:00+00:00"));
+        let words = fake.get_words(&STUDENT).await.unwrap();
+        let pipeline = SyncPipeline::new(
+            unused_pool(),
+            Box::new(AnkiSink::new(Anki::new("http://127.0.0.1:1".to_string()), DuplicateOptions::default())),
+            NoteSettings::default(),
+            SyncOptions::default(),
+            "Skyeng".to_string(),
+        );
+        let meanings = pipeline.fetch_meanings(&mut fake, &words).await.unwrap();
+        let pairs = meanings.iter()
+            .map(|(w, m)| (w.wordset.title.as_str(), m.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(pairs, vec![("Communication", "deter"), ("Work", "chat")]);
+    }
+
+    #[tokio::test]
+    async fn test_meanings_in_chunks() {
+        let mut fake = FakeWords::from_fixtures();
+        let data = fake.words.iter()
+            .map(|w| w.word.clone())
+            .cycle()
+            .take(MEANINGS_CHUNK_SIZE * 2 + 1)
+            .collect::<Vec<_>>();
+        let meanings = get_meanings(&mut fake, &data).await.unwrap();
+        assert_eq!(fake.requested, vec![MEANINGS_CHUNK_SIZE, MEANINGS_CHUNK_SIZE, 1]);
+        assert!(meanings.iter().any(|m| m.text == "deter"));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires Postgres, run `docker compose up -d` in docker/"]
+    async fn test_sync_to_fake_anki() {
+        let pool = fresh_database("skynki_test_sync");
+        embedded_migrations::run(&pool.get().unwrap()).unwrap();
+        let fake = FakeAnki::start().await;
+        let mut words = FakeWords::from_fixtures();
+        let settings = NoteSettings {
+            deck: DeckOptions { deck: "Skyeng".to_string(), subdecks: true, wordset_tags: true },
+            ..NoteSettings::default()
+        };
+        let mut pipeline = SyncPipeline::new(
+            pool.clone(),
+            Box::new(AnkiSink::new(Anki::new(fake.url.clone()), settings.duplicates.clone())),
+            settings,
+            SyncOptions::default(),
+            "Skyeng".to_string(),
+        );
+
+        let report = pipeline.run(&mut words, STUDENT).await.unwrap();
+        assert_eq!(report, RunReport { added: 2, ..RunReport::default() });
+        let notes = fake.notes();
+        let decks = notes.iter().map(|n| n.deck.as_str()).collect::<Vec<_>>();
+        assert_eq!(decks, vec!["Skyeng::Communication::Informal communication", "Skyeng::Work::Office talk"]);
+        assert!(notes.iter().all(|n| n.model == "Cloze"));
+        assert!(notes[0].fields["Text"].contains("deter"), "{:?}", notes[0]);
+        assert!(notes[0].tags.contains(&"Communication".to_string()));
+        assert_eq!(fake.syncs(), 1);
+        assert_eq!(get_words(&pool, STUDENT.into()).unwrap().len(), 2);
+
+        // the cursor is past both words, a new meaning of an old word set is picked up
+        words.words.push(word(175493, "2022-02-14T00:00:00+00:00"));
+        let report = pipeline.run(&mut words, STUDENT).await.unwrap();
+        assert_eq!(report, RunReport { skipped: 1, ..RunReport::default() });
+        assert_eq!(fake.notes().len(), 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires Postgres, run `docker compose up -d` in docker/"]
+    async fn test_sync_to_file_twice() {
+        let pool = fresh_database("skynki_test_sync_file");
+        embedded_migrations::run(&pool.get().unwrap()).unwrap();
+        let path = std::env::temp_dir().join(format!("skynki-sync-{}.json", std::process::id()));
+        let mut words = FakeWords::from_fixtures();
+        let mut pipeline = SyncPipeline::new(
+            pool.clone(),
+            Box::new(JsonSink::new(path.clone())),
+            NoteSettings::default(),
+            SyncOptions::default(),
+            "Skyeng".to_string(),
+        );
+        let report = pipeline.run(&mut words, STUDENT).await.unwrap();
+        assert_eq!(report, RunReport { added: 2, ..RunReport::default() });
+
+        // words written without a note id are not written again after a reset of the cursor
+        let key = CursorKey::new(USER, STUDENT, "Skyeng");
+        save_last_update(&pool, &key, &DateTime::UNIX_EPOCH.naive_utc()).unwrap();
+        let report = pipeline.run(&mut words, STUDENT).await.unwrap();
+        assert_eq!(report, RunReport { skipped: 2, ..RunReport::default() });
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(text.lines().count(), 2);
+    }
+}
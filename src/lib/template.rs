@@ -0,0 +1,443 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use serde::Deserialize;
+use super::anki::{DuplicateScopeOptions, Fields, Note, Options};
+use super::cloze::ClozeBuilder;
+use super::errors::{Error, Result};
+use super::skyeng::{Meaning, MeaningImage, WordSetData};
+
+/// Which images of a meaning are attached to the note
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PictureMode {
+    None,
+    First,
+    All,
+}
+
+impl FromStr for PictureMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(PictureMode::None),
+            "first" => Ok(PictureMode::First),
+            "all" => Ok(PictureMode::All),
+            _ => Err(Error::UserError { message: "picture mode must be one of: none, first, all" }),
+        }
+    }
+}
+
+impl PictureMode {
+    pub fn select<'a>(&self, images: &'a [MeaningImage]) -> &'a [MeaningImage] {
+        match self {
+            PictureMode::None => &[],
+            PictureMode::First => &images[..images.len().min(1)],
+            PictureMode::All => images,
+        }
+    }
+}
+
+/// Where notes are placed and how they are tagged
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DeckOptions {
+    pub deck: String,
+    /// places notes into `Deck::Title::Subtitle` of their wordset
+    pub subdecks: bool,
+    /// tags notes with the title and subtitle of their wordset
+    pub wordset_tags: bool,
+}
+
+impl Default for DeckOptions {
+    fn default() -> Self {
+        Self {
+            deck: "Default".to_string(),
+            subdecks: false,
+            wordset_tags: false,
+        }
+    }
+}
+
+impl DeckOptions {
+    pub fn deck_name(&self, wordset: &WordSetData) -> String {
+        if !self.subdecks {
+            return self.deck.clone();
+        }
+        // "::" separates deck levels, so it must not appear inside of a title
+        std::iter::once(self.deck.clone())
+            .chain([&wordset.title, &wordset.subtitle].iter()
+                .map(|name| name.replace("::", ":").trim().to_string())
+                .filter(|name| !name.is_empty()))
+            .collect::<Vec<_>>()
+            .join("::")
+    }
+
+    pub fn tags(&self, wordset: &WordSetData) -> Vec<String> {
+        let mut tags = vec!["skyeng".to_string()];
+        if self.wordset_tags {
+            tags.extend([&wordset.title, &wordset.subtitle].iter()
+                .map(|name| sanitize_tag(name))
+                .filter(|tag| !tag.is_empty()));
+        }
+        tags
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateScope {
+    Deck,
+    Collection,
+}
+
+impl FromStr for DuplicateScope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "deck" => Ok(DuplicateScope::Deck),
+            "collection" => Ok(DuplicateScope::Collection),
+            _ => Err(Error::UserError { message: "duplicate scope must be one of: deck, collection" }),
+        }
+    }
+}
+
+/// How Anki decides that a note is a duplicate of an existing one
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DuplicateOptions {
+    pub scope: DuplicateScope,
+    /// deck to look for duplicates in, the deck of the note if not set
+    pub deck: Option<String>,
+    pub check_children: bool,
+    pub check_all_models: bool,
+    /// looks for duplicates with `findNotes` before adding and skips them,
+    /// otherwise Anki refuses to add them and they are reported as failed
+    pub find_before_add: bool,
+}
+
+impl Default for DuplicateOptions {
+    fn default() -> Self {
+        Self {
+            scope: DuplicateScope::Deck,
+            deck: None,
+            check_children: false,
+            check_all_models: false,
+            find_before_add: false,
+        }
+    }
+}
+
+impl DuplicateOptions {
+    pub fn to_options(&self, deck_name: &str) -> Options {
+        Options {
+            allow_duplicate: false,
+            duplicate_scope: match self.scope {
+                DuplicateScope::Deck => "deck".to_string(),
+                DuplicateScope::Collection => "collection".to_string(),
+            },
+            duplicate_scope_options: Some(DuplicateScopeOptions {
+                deck_name: self.deck.clone().unwrap_or_else(|| deck_name.to_string()),
+                check_children: self.check_children,
+                check_all_models: self.check_all_models,
+            }),
+        }
+    }
+
+    /// `findNotes` query for the notes Anki treats as duplicates of the note:
+    /// notes with the same first field in the same deck and model.
+    /// `None` if the note has no such field.
+    pub fn query(&self, note: &Note, first_field: &str) -> Option<String> {
+        let value = note.fields.get(first_field)?;
+        let mut terms = Vec::new();
+        if self.scope == DuplicateScope::Deck {
+            let deck = escape_search(self.deck.as_ref().unwrap_or(&note.deck_name));
+            terms.push(format!("\"deck:{}\"", deck));
+            if !self.check_children {
+                // children of the same escaped name, only the wildcard is left unescaped
+                terms.push(format!("-\"deck:{}{}*\"", deck, escape_search("::")));
+            }
+        }
+        if !self.check_all_models {
+            terms.push(format!("\"note:{}\"", escape_search(&note.model_name)));
+        }
+        terms.push(format!("\"{}:{}\"", escape_search(first_field), escape_search(value)));
+        Some(terms.join(" "))
+    }
+}
+
+/// Escapes characters with a special meaning in Anki search
+fn escape_search(text: &str) -> String {
+    text.chars().fold(String::with_capacity(text.len()), |mut acc, c| {
+        if matches!(c, '\\' | '"' | '*' | '_' | ':' | '(' | ')' | '-') {
+            acc.push('\\');
+        }
+        acc.push(c);
+        acc
+    })
+}
+
+/// Anki splits tags by whitespace, so words of a tag are joined with `_`
+fn sanitize_tag(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join("_")
+        .replace('"', "")
+}
+
+/// Describes how a `Meaning` is rendered into the fields of an Anki note type
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NoteTemplate {
+    pub model_name: String,
+    /// field name -> template string with placeholders
+    pub fields: BTreeMap<String, String>,
+    /// field, which gets the pronunciation of the word
+    pub audio_field: String,
+    /// field, which gets the pronunciation of the examples
+    pub example_audio_field: Option<String>,
+    /// field, which gets the images of the word
+    pub picture_field: String,
+    pub pictures: PictureMode,
+    /// adds the word itself as `c2` to the `{cloze}` placeholder
+    pub cloze_headword: bool,
+}
+
+impl Default for NoteTemplate {
+    fn default() -> Self {
+        let mut fields = BTreeMap::new();
+        fields.insert("Text".to_string(), "{cloze}".to_string());
+        Self {
+            model_name: "Cloze".to_string(),
+            fields,
+            audio_field: "Extra".to_string(),
+            example_audio_field: None,
+            picture_field: "Extra".to_string(),
+            pictures: PictureMode::First,
+            cloze_headword: false,
+        }
+    }
+}
+
+impl NoteTemplate {
+    pub fn render(&self, meaning: &Meaning) -> Fields {
+        let values = self.placeholder_values(meaning);
+        self.fields.iter()
+            .map(|(field, template)| {
+                let rendered = values.iter().fold(template.clone(), |acc, (name, value)| {
+                    acc.replace(&format!("{{{}}}", name), value)
+                });
+                (field.clone(), rendered)
+            })
+            .collect()
+    }
+
+    /// Rendered fields as a single text to detect changes of the meaning between runs
+    pub fn render_text(&self, meaning: &Meaning) -> String {
+        self.render(meaning)
+            .into_values()
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn placeholder_values(&self, meaning: &Meaning) -> Vec<(&'static str, String)> {
+        let examples = meaning.examples.iter()
+            .map(|e| e.text.clone())
+            .collect::<Vec<_>>()
+            .join("<br>");
+        let alternatives = meaning.alternatives.iter()
+            .flatten()
+            .map(|a| match &a.translation {
+                Some(translation) => format!("{} - {}", a.text, translation.text),
+                None => a.text.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("<br>");
+        let images = meaning.images.iter()
+            .map(|i| format!("<img src=\"{}\">", i.url))
+            .collect::<Vec<_>>()
+            .join("");
+        vec![
+            ("text", meaning.text.clone()),
+            ("translation", meaning.translation.text.clone()),
+            ("transcription", meaning.transcription.clone()),
+            ("definition", meaning.definition.text.clone()),
+            ("examples", examples),
+            ("alternatives", alternatives),
+            ("images", images),
+            ("sound_url", meaning.sound_url.clone()),
+            ("cloze", ClozeBuilder::new(self.cloze_headword).build(meaning)),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lib::skyeng::test::sample_meaning;
+    use crate::lib::anki::Anki;
+    use crate::lib::testing::anki::FakeAnki;
+
+    #[test]
+    fn test_default_template() {
+        let fields = NoteTemplate::default().render(&sample_meaning());
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields["Text"], ClozeBuilder::default().build(&sample_meaning()));
+    }
+
+    #[test]
+    fn test_custom_template() {
+        let mut fields = BTreeMap::new();
+        fields.insert("Front".to_string(), "{text} [{transcription}]".to_string());
+        fields.insert("Back".to_string(), "{translation}<br>{examples}".to_string());
+        fields.insert("Picture".to_string(), "{images}{unknown}".to_string());
+        let template = NoteTemplate {
+            model_name: "Skyeng".to_string(),
+            fields,
+            audio_field: "Front".to_string(),
+            example_audio_field: None,
+            picture_field: "Picture".to_string(),
+            pictures: PictureMode::None,
+            cloze_headword: false,
+        };
+        let fields = template.render(&sample_meaning());
+        assert_eq!(fields["Front"], "deter [dɪˈtɜː]");
+        assert_eq!(
+            fields["Back"],
+            "удерживать<br>I told him I wasn't interested, but he wasn't [deterred].<br>The rain didn’t [deter] people from coming to the game."
+        );
+        assert_eq!(
+            fields["Picture"],
+            "<img src=\"https://cdn-user77752.skyeng.ru/resized-images/200x150/png/50/5a677c4b4a356e7a4a3fc243deb73676.png\">{unknown}"
+        );
+    }
+
+    #[test]
+    fn test_picture_mode() {
+        let mut meaning = sample_meaning();
+        meaning.images.push(MeaningImage { url: "https://cdn.skyeng.ru/second.png".to_string() });
+        assert_eq!(PictureMode::None.select(&meaning.images).len(), 0);
+        assert_eq!(PictureMode::First.select(&meaning.images).len(), 1);
+        assert_eq!(PictureMode::All.select(&meaning.images).len(), 2);
+        assert_eq!(PictureMode::First.select(&[]).len(), 0);
+        assert!("some".parse::<PictureMode>().is_err());
+    }
+
+    fn wordset() -> WordSetData {
+        WordSetData {
+            id: 62494171,
+            title: "Communication".to_string(),
+            subtitle: "Informal communication".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_deck_name() {
+        let mut options = DeckOptions {
+            deck: "Skyeng".to_string(),
+            subdecks: false,
+            wordset_tags: false,
+        };
+        assert_eq!(options.deck_name(&wordset()), "Skyeng");
+        options.subdecks = true;
+        assert_eq!(options.deck_name(&wordset()), "Skyeng::Communication::Informal communication");
+        let wordset = WordSetData {
+            id: 1,
+            title: "Travel::Airport ".to_string(),
+            subtitle: "".to_string(),
+        };
+        assert_eq!(options.deck_name(&wordset), "Skyeng::Travel:Airport");
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut options = DeckOptions::default();
+        assert_eq!(options.tags(&wordset()), vec!["skyeng"]);
+        options.wordset_tags = true;
+        assert_eq!(options.tags(&wordset()), vec!["skyeng", "Communication", "Informal_communication"]);
+    }
+
+    #[test]
+    fn test_duplicate_options() {
+        let options = DuplicateOptions::default().to_options("Skyeng");
+        assert_eq!(options.duplicate_scope, "deck");
+        assert_eq!(options.duplicate_scope_options.unwrap().deck_name, "Skyeng");
+
+        let duplicates = DuplicateOptions {
+            scope: DuplicateScope::Collection,
+            deck: Some("English".to_string()),
+            check_children: true,
+            check_all_models: true,
+            find_before_add: false,
+        };
+        let options = duplicates.to_options("Skyeng");
+        assert_eq!(options.duplicate_scope, "collection");
+        let scope = options.duplicate_scope_options.unwrap();
+        assert_eq!(scope.deck_name, "English");
+        assert!(scope.check_children);
+        assert!(scope.check_all_models);
+    }
+
+    #[test]
+    fn test_duplicate_query() {
+        let mut fields = BTreeMap::new();
+        fields.insert("Front".to_string(), "deter: \"to_stop\"".to_string());
+        let note = Note {
+            deck_name: "Skyeng::Communication".to_string(),
+            model_name: "Basic".to_string(),
+            fields,
+            options: DuplicateOptions::default().to_options("Skyeng"),
+            tags: vec![],
+            audio: vec![],
+            picture: vec![],
+        };
+        let duplicates = DuplicateOptions::default();
+        assert_eq!(
+            duplicates.query(&note, "Front").unwrap(),
+            r#""deck:Skyeng\:\:Communication" -"deck:Skyeng\:\:Communication\:\:*" "note:Basic" "Front:deter\: \"to\_stop\"""#
+        );
+        assert!(duplicates.query(&note, "Back").is_none());
+
+        let duplicates = DuplicateOptions {
+            scope: DuplicateScope::Collection,
+            check_all_models: true,
+            ..DuplicateOptions::default()
+        };
+        assert_eq!(duplicates.query(&note, "Front").unwrap(), r#""Front:deter\: \"to\_stop\"""#);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_query_escaped_deck() {
+        let fake = FakeAnki::start().await;
+        let anki = Anki::new(fake.url.clone());
+        let note = |deck: &str| Note {
+            deck_name: deck.to_string(),
+            model_name: "Basic".to_string(),
+            fields: [("Front", "deter"), ("Back", "удерживать")].iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            options: DuplicateOptions::default().to_options(deck),
+            tags: vec![],
+            audio: vec![],
+            picture: vec![],
+        };
+        for deck in ["Work_1*: talk", "Work_1*: talk::Office", "WorkX1*: talk", "WorkX1*: talk::Office"] {
+            anki.create_deck(deck).await.unwrap();
+        }
+        let results = anki.add_notes(vec![note("WorkX1*: talk"), note("Work_1*: talk::Office")]).await.unwrap();
+        assert!(results.iter().all(|r| r.is_ok()), "{:?}", results);
+
+        let query = DuplicateOptions::default().query(&note("Work_1*: talk"), "Front").unwrap();
+        assert_eq!(
+            query,
+            r#""deck:Work\_1\*\: talk" -"deck:Work\_1\*\: talk\:\:*" "note:Basic" "Front:deter""#
+        );
+        // neither the deck with `X` in place of `_` nor the child deck match
+        assert!(anki.find_notes(&query).await.unwrap().is_empty());
+
+        let children = DuplicateOptions { check_children: true, ..DuplicateOptions::default() };
+        let found = anki.find_notes(&children.query(&note("Work_1*: talk"), "Front").unwrap()).await.unwrap();
+        assert_eq!(found, vec![*results[1].as_ref().unwrap()]);
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use axum::extract::State;
+use axum::routing::post;
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use crate::lib::anki::Fields;
+
+/// Id of the first note, as Anki note ids are creation times in millis
+const FIRST_NOTE_ID: u64 = 1496198395707;
+
+/// Note as stored by the fake, attachments are already appended to the fields
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FakeNote {
+    pub id: u64,
+    pub deck: String,
+    pub model: String,
+    pub fields: Fields,
+    pub tags: Vec<String>,
+}
+
+struct Collection {
+    profiles: Vec<String>,
+    profile: String,
+    decks: Vec<String>,
+    /// model name -> field names, the first one is checked for duplicates
+    models: BTreeMap<String, Vec<String>>,
+    notes: Vec<FakeNote>,
+    media: BTreeMap<String, Vec<u8>>,
+    syncs: usize,
+}
+
+impl Default for Collection {
+    fn default() -> Self {
+        let fields = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let mut models = BTreeMap::new();
+        models.insert("Basic".to_string(), fields(&["Front", "Back"]));
+        models.insert("Basic (and reversed card)".to_string(), fields(&["Front", "Back"]));
+        models.insert("Cloze".to_string(), fields(&["Text", "Extra"]));
+        Self {
+            profiles: vec!["User 1".to_string()],
+            profile: "User 1".to_string(),
+            decks: vec!["Default".to_string()],
+            models,
+            notes: Vec::new(),
+            media: BTreeMap::new(),
+            syncs: 0,
+        }
+    }
+}
+
+/// In-process AnkiConnect (API version 6) keeping the collection in memory.
+///
+/// Actions fail with the messages of the plugin: duplicates, missing decks, models and notes.
+/// Unlike the plugin it also rejects fields missing in the model instead of dropping them,
+/// so a wrong template fails the test. Attachments by url are recorded in the media without content.
+/// Lives as long as the runtime of the test.
+pub(crate) struct FakeAnki {
+    pub url: String,
+    collection: Arc<Mutex<Collection>>,
+}
+
+impl FakeAnki {
+    pub async fn start() -> Self {
+        let collection = Arc::new(Mutex::new(Collection::default()));
+        let app = Router::new()
+            .route("/", post(request))
+            .with_state(collection.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        Self { url, collection }
+    }
+
+    pub fn add_model(&self, name: &str, fields: &[&str]) {
+        self.collection.lock().unwrap().models
+            .insert(name.to_string(), fields.iter().map(|f| f.to_string()).collect());
+    }
+
+    pub fn add_profile(&self, name: &str) {
+        self.collection.lock().unwrap().profiles.push(name.to_string());
+    }
+
+    pub fn notes(&self) -> Vec<FakeNote> {
+        self.collection.lock().unwrap().notes.clone()
+    }
+
+    pub fn decks(&self) -> Vec<String> {
+        self.collection.lock().unwrap().decks.clone()
+    }
+
+    pub fn media(&self) -> BTreeMap<String, Vec<u8>> {
+        self.collection.lock().unwrap().media.clone()
+    }
+
+    pub fn profile(&self) -> String {
+        self.collection.lock().unwrap().profile.clone()
+    }
+
+    pub fn syncs(&self) -> usize {
+        self.collection.lock().unwrap().syncs
+    }
+}
+
+async fn request(State(collection): State<Arc<Mutex<Collection>>>, Json(body): Json<Value>) -> Json<Value> {
+    let mut collection = collection.lock().unwrap();
+    Json(envelope(collection.handle(&body)))
+}
+
+fn envelope(result: Result<Value, String>) -> Value {
+    match result {
+        Ok(result) => json!({ "result": result, "error": null }),
+        Err(error) => json!({ "result": null, "error": error }),
+    }
+}
+
+fn param<'a>(params: &'a Value, name: &str) -> Result<&'a Value, String> {
+    params.get(name).ok_or_else(|| format!("missing parameter: {}", name))
+}
+
+fn string<'a>(params: &'a Value, name: &str) -> Result<&'a str, String> {
+    param(params, name)?.as_str().ok_or_else(|| format!("{} is not a string", name))
+}
+
+fn fields(value: &Value) -> Result<Fields, String> {
+    serde_json::from_value(value.clone()).map_err(|e| format!("invalid fields: {}", e))
+}
+
+impl Collection {
+    fn handle(&mut self, body: &Value) -> Result<Value, String> {
+        if body.get("version").and_then(Value::as_u64) != Some(6) {
+            return Err("unsupported version, skynki speaks version 6".to_string());
+        }
+        let action = string(body, "action")?;
+        let params = body.get("params").cloned().unwrap_or(Value::Null);
+        match action {
+            "version" => Ok(json!(6)),
+            "sync" => {
+                self.syncs += 1;
+                Ok(Value::Null)
+            }
+            "loadProfile" => {
+                let name = string(&params, "name")?;
+                if !self.profiles.iter().any(|p| p == name) {
+                    return Err(format!("profile was not found: {}", name));
+                }
+                self.profile = name.to_string();
+                Ok(json!(true))
+            }
+            "deckNames" => Ok(json!(self.decks)),
+            "createDeck" => Ok(json!(self.create_deck(string(&params, "deck")?))),
+            "modelFieldNames" => {
+                let model = string(&params, "modelName")?;
+                self.models.get(model)
+                    .map(|fields| json!(fields))
+                    .ok_or_else(|| format!("model was not found: {}", model))
+            }
+            "findNotes" => {
+                let query = Query::parse(string(&params, "query")?);
+                let ids = self.notes.iter()
+                    .filter(|n| query.matches(n))
+                    .map(|n| n.id)
+                    .collect::<Vec<_>>();
+                Ok(json!(ids))
+            }
+            "addNote" => self.add_note(param(&params, "note")?).map(|id| json!(id)),
+            "updateNoteFields" => self.update_note_fields(param(&params, "note")?).map(|_| Value::Null),
+            "storeMediaFile" => {
+                let filename = string(&params, "filename")?;
+                let data = base64::decode(string(&params, "data")?).map_err(|e| e.to_string())?;
+                self.media.insert(filename.to_string(), data);
+                Ok(json!(filename))
+            }
+            "multi" => {
+                let actions = param(&params, "actions")?
+                    .as_array()
+                    .ok_or("actions is not a list")?;
+                Ok(Value::Array(actions.iter().map(|a| envelope(self.handle(a))).collect()))
+            }
+            _ => Err("unsupported action".to_string()),
+        }
+    }
+
+    /// Id of the deck, the deck and its parents are created if they are missing
+    fn create_deck(&mut self, deck: &str) -> usize {
+        let parts = deck.split("::").collect::<Vec<_>>();
+        for end in 1..=parts.len() {
+            let name = parts[..end].join("::");
+            if !self.decks.contains(&name) {
+                self.decks.push(name);
+            }
+        }
+        self.decks.iter().position(|d| d == deck).unwrap() + 1
+    }
+
+    fn model_fields(&self, model: &str) -> Result<&Vec<String>, String> {
+        self.models.get(model).ok_or_else(|| format!("model was not found: {}", model))
+    }
+
+    fn check_fields<'a>(&self, model: &str, names: impl IntoIterator<Item = &'a String>) -> Result<(), String> {
+        let known = self.model_fields(model)?;
+        match names.into_iter().find(|n| !known.contains(n)) {
+            Some(name) => Err(format!("field was not found in model {}: {}", model, name)),
+            None => Ok(()),
+        }
+    }
+
+    fn add_note(&mut self, note: &Value) -> Result<u64, String> {
+        let deck = string(note, "deckName")?.to_string();
+        let model = string(note, "modelName")?.to_string();
+        let mut fields = fields(param(note, "fields")?)?;
+        let model_fields = self.model_fields(&model)?.clone();
+        if !self.decks.contains(&deck) {
+            return Err(format!("deck was not found: {}", deck));
+        }
+        self.check_fields(&model, fields.keys())?;
+        self.attach(&model, note, &mut fields)?;
+        for name in model_fields.iter() {
+            fields.entry(name.clone()).or_default();
+        }
+        let first = fields[&model_fields[0]].trim().to_string();
+        if first.is_empty() {
+            return Err("cannot create note because it is empty".to_string());
+        }
+        let options = note.get("options").cloned().unwrap_or(Value::Null);
+        if !options.get("allowDuplicate").and_then(Value::as_bool).unwrap_or(false)
+            && self.is_duplicate(&deck, &model, &first, &options) {
+            return Err("cannot create note because it is a duplicate".to_string());
+        }
+        let id = FIRST_NOTE_ID + self.notes.len() as u64;
+        let tags = note.get("tags")
+            .map(|t| serde_json::from_value(t.clone()).map_err(|e| format!("invalid tags: {}", e)))
+            .transpose()?
+            .unwrap_or_default();
+        self.notes.push(FakeNote { id, deck, model, fields, tags });
+        Ok(id)
+    }
+
+    /// Links audio and pictures of the note into their fields, as AnkiConnect downloads them
+    fn attach(&mut self, model: &str, note: &Value, fields: &mut Fields) -> Result<(), String> {
+        for (kind, format) in [("audio", "[sound:{}]"), ("picture", "<img src=\"{}\">")] {
+            for attachment in note.get(kind).and_then(Value::as_array).into_iter().flatten() {
+                let filename = string(attachment, "filename")?.to_string();
+                let targets = attachment.get("fields").and_then(Value::as_array).cloned().unwrap_or_default();
+                for target in targets.iter().filter_map(Value::as_str) {
+                    self.check_fields(model, [&target.to_string()])?;
+                    fields.entry(target.to_string())
+                        .or_default()
+                        .push_str(&format.replace("{}", &filename));
+                }
+                self.media.entry(filename).or_default();
+            }
+        }
+        Ok(())
+    }
+
+    fn is_duplicate(&self, deck: &str, model: &str, first: &str, options: &Value) -> bool {
+        let scope_options = options.get("duplicateScopeOptions").unwrap_or(&Value::Null);
+        let in_deck = options.get("duplicateScope").and_then(Value::as_str) == Some("deck");
+        let deck = scope_options.get("deckName").and_then(Value::as_str).unwrap_or(deck);
+        let children = scope_options.get("checkChildren").and_then(Value::as_bool).unwrap_or(false);
+        let all_models = scope_options.get("checkAllModels").and_then(Value::as_bool).unwrap_or(false);
+        self.notes.iter()
+            .filter(|n| all_models || n.model == model)
+            .filter(|n| !in_deck || n.deck == deck || (children && n.deck.starts_with(&format!("{}::", deck))))
+            .any(|n| {
+                let first_field = &self.models[&n.model][0];
+                n.fields.get(first_field).map(|v| v.trim()) == Some(first)
+            })
+    }
+
+    fn update_note_fields(&mut self, update: &Value) -> Result<(), String> {
+        let id = param(update, "id")?.as_u64().ok_or("id is not a number")?;
+        let mut fields = fields(param(update, "fields")?)?;
+        let model = self.notes.iter()
+            .find(|n| n.id == id)
+            .map(|n| n.model.clone())
+            .ok_or_else(|| format!("Note was not found: {}", id))?;
+        self.check_fields(&model, fields.keys())?;
+        self.attach(&model, update, &mut fields)?;
+        let note = self.notes.iter_mut().find(|n| n.id == id).unwrap();
+        note.fields.extend(fields);
+        Ok(())
+    }
+}
+
+/// Subset of the Anki search syntax used by skynki:
+/// `deck:`, `note:` and `field:` terms, quoting, `-` negation, `*` and `_` wildcards, `\` escapes
+struct Query {
+    terms: Vec<(bool, Term)>,
+}
+
+enum Term {
+    Deck(Pattern),
+    Model(Pattern),
+    Field(String, Pattern),
+    /// text in any of the fields
+    Text(Pattern),
+}
+
+/// Search text as characters with the flag of an escaped one, escaped wildcards match themselves
+type Pattern = Vec<(char, bool)>;
+
+impl Query {
+    fn parse(query: &str) -> Self {
+        let mut terms = Vec::new();
+        let mut token = Pattern::new();
+        let mut negated = false;
+        let mut quoted = false;
+        let mut chars = query.chars();
+        let mut flush = |token: &mut Pattern, negated: &mut bool| {
+            if !token.is_empty() {
+                terms.push((*negated, Term::parse(std::mem::take(token))));
+            }
+            *negated = false;
+        };
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => token.extend(chars.next().map(|c| (c, true))),
+                '"' => quoted = !quoted,
+                '-' if token.is_empty() && !quoted => negated = true,
+                ' ' if !quoted => flush(&mut token, &mut negated),
+                c => token.push((c, false)),
+            }
+        }
+        flush(&mut token, &mut negated);
+        Self { terms }
+    }
+
+    fn matches(&self, note: &FakeNote) -> bool {
+        self.terms.iter().all(|(negated, term)| term.matches(note) != *negated)
+    }
+}
+
+impl Term {
+    fn parse(token: Pattern) -> Self {
+        let colon = token.iter().position(|&(c, escaped)| c == ':' && !escaped);
+        let (key, value) = match colon {
+            Some(i) => (token[..i].iter().map(|(c, _)| *c).collect::<String>(), token[i + 1..].to_vec()),
+            None => return Term::Text(token),
+        };
+        match key.to_lowercase().as_str() {
+            "deck" => Term::Deck(value),
+            "note" => Term::Model(value),
+            _ => Term::Field(key, value),
+        }
+    }
+
+    fn matches(&self, note: &FakeNote) -> bool {
+        match self {
+            // like in Anki, a deck matches together with its children
+            Term::Deck(pattern) => note.deck.match_indices("::")
+                .map(|(i, _)| &note.deck[..i])
+                .chain([note.deck.as_str()])
+                .any(|deck| glob(pattern, deck)),
+            Term::Model(pattern) => glob(pattern, &note.model),
+            Term::Field(name, pattern) => note.fields.iter()
+                .any(|(field, value)| field.eq_ignore_ascii_case(name) && glob(pattern, value)),
+            Term::Text(pattern) => {
+                let mut contains = vec![('*', false)];
+                contains.extend(pattern.iter().copied());
+                contains.push(('*', false));
+                note.fields.values().any(|value| glob(&contains, value))
+            }
+        }
+    }
+}
+
+/// Case insensitive match of the whole text, `*` is any text and `_` any character
+fn glob(pattern: &[(char, bool)], text: &str) -> bool {
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+    let pattern = pattern.iter()
+        .map(|&(c, escaped)| (c.to_lowercase().next().unwrap_or(c), escaped))
+        .collect::<Vec<_>>();
+    fn step(pattern: &[(char, bool)], text: &[char]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some((('*', false), rest)) => (0..=text.len()).any(|i| step(rest, &text[i..])),
+            Some((('_', false), rest)) => !text.is_empty() && step(rest, &text[1..]),
+            Some(((c, _), rest)) => text.first() == Some(c) && step(rest, &text[1..]),
+        }
+    }
+    step(&pattern, &text)
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use async_trait::async_trait;
+use axum::extract::{Form, Path, Query};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use tokio::net::TcpListener;
+use crate::lib::errors::Result;
+use crate::lib::skyeng::{Meaning, SkyengUrls, WordData, WordOfSet, WordSet, WordSetData, Words, WordsSource};
+
+pub(crate) const USER: &str = "parent@mail.com";
+pub(crate) const PASSWORD: &str = "secret";
+pub(crate) const STUDENT: u32 = 6605911;
+const CSRF: &str = "2f6c1a9e8b7d4c3a";
+const TOKEN: &str = "mock-token";
+
+const LOGIN_PAGE: &str = include_str!("../../../fixtures/skyeng/login.html");
+const WORDSETS: [&str; 2] = [
+    include_str!("../../../fixtures/skyeng/wordsets_page_1.json"),
+    include_str!("../../../fixtures/skyeng/wordsets_page_2.json"),
+];
+const WORDS: [(u32, &str); 2] = [
+    (62494171, include_str!("../../../fixtures/skyeng/words_62494171.json")),
+    (62494172, include_str!("../../../fixtures/skyeng/words_62494172.json")),
+];
+const MEANINGS: &str = include_str!("../../../fixtures/skyeng/meanings.json");
+
+/// Words of the recorded fixtures kept in memory, no network involved
+#[derive(Debug, Clone)]
+pub(crate) struct FakeWords {
+    pub word_sets: Vec<WordSetData>,
+    pub words: Vec<WordOfSet>,
+    pub meanings: Vec<Meaning>,
+    /// amount of meanings asked by each request
+    pub requested: Vec<usize>,
+}
+
+impl FakeWords {
+    pub fn from_fixtures() -> Self {
+        let word_sets = WORDSETS.iter()
+            .flat_map(|page| serde_json::from_str::<WordSet>(page).unwrap().data)
+            .collect::<Vec<_>>();
+        let words = WORDS.iter()
+            .flat_map(|(id, page)| {
+                let word_set = word_sets.iter().find(|s| s.id == *id).unwrap();
+                WordOfSet::new(word_set, serde_json::from_str::<Words>(page).unwrap().data)
+            })
+            .collect();
+        Self {
+            word_sets,
+            words,
+            meanings: serde_json::from_str(MEANINGS).unwrap(),
+            requested: Vec::new(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl WordsSource for FakeWords {
+    fn user(&self) -> &str {
+        USER
+    }
+
+    async fn get_word_sets(&mut self, _student_id: &u32) -> Result<Vec<WordSetData>> {
+        Ok(self.word_sets.clone())
+    }
+
+    async fn get_words(&mut self, _student_id: &u32) -> Result<Vec<WordOfSet>> {
+        Ok(self.words.clone())
+    }
+
+    async fn get_meanings(&mut self, words: &[WordData]) -> Result<Vec<Meaning>> {
+        self.requested.push(words.len());
+        Ok(self.meanings.iter()
+            .filter(|m| words.iter().any(|w| w.meaning_id == m.id))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Serves the fixtures on the paths of the Skyeng services, accepting `USER` with `PASSWORD`.
+/// Lives as long as the runtime of the test.
+pub(crate) async fn mock_server() -> SkyengUrls {
+    let app = Router::new()
+        .route("/login", get(login_page))
+        .route("/frame/login-submit", post(login_submit))
+        .route("/user-api/v1/auth/jwt", post(jwt))
+        .route("/api/for-vimbox/v1/wordsets.json", get(wordsets))
+        .route("/api/v1/wordsets/:id/words.json", get(words))
+        .route("/api/for-services/v2/meanings", get(meanings));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    SkyengUrls {
+        id: url.clone(),
+        words: url.clone(),
+        dictionary: url,
+    }
+}
+
+async fn login_page() -> impl IntoResponse {
+    ([(header::SET_COOKIE, "session=1; Path=/")], axum::response::Html(LOGIN_PAGE))
+}
+
+async fn login_submit(Form(form): Form<HashMap<String, String>>) -> StatusCode {
+    let valid = form.get("csrfToken").map(String::as_str) == Some(CSRF)
+        && form.get("username").map(String::as_str) == Some(USER)
+        && form.get("password").map(String::as_str) == Some(PASSWORD);
+    if valid { StatusCode::OK } else { StatusCode::UNAUTHORIZED }
+}
+
+async fn jwt() -> impl IntoResponse {
+    let cookie = format!("token_global={}; Expires=Thu, 01 Jan 2099 00:00:00 GMT; Path=/", TOKEN);
+    [(header::SET_COOKIE, cookie)]
+}
+
+fn authorized(headers: &HeaderMap) -> bool {
+    headers.get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        == Some(format!("Bearer {}", TOKEN).as_str())
+}
+
+fn json(body: String) -> Response {
+    ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+async fn wordsets(headers: HeaderMap, Query(query): Query<HashMap<String, String>>) -> Response {
+    if !authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let page = query.get("page").and_then(|p| p.parse::<usize>().ok()).unwrap_or(1);
+    match WORDSETS.get(page.wrapping_sub(1)) {
+        Some(body) => json(body.to_string()),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn words(headers: HeaderMap, Path(id): Path<u32>) -> Response {
+    if !authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match WORDS.iter().find(|(set, _)| *set == id) {
+        Some((_, body)) => json(body.to_string()),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn meanings(headers: HeaderMap, Query(query): Query<HashMap<String, String>>) -> Response {
+    if !authorized(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let ids = query.get("ids").cloned().unwrap_or_default();
+    let ids = ids.split(',').collect::<Vec<_>>();
+    let meanings = serde_json::from_str::<Vec<serde_json::Value>>(MEANINGS).unwrap()
+        .into_iter()
+        .filter(|m| ids.contains(&m["id"].to_string().as_str()))
+        .collect::<Vec<_>>();
+    json(serde_json::to_string(&meanings).unwrap())
+}
@@ -0,0 +1,152 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use super::anki::Note;
+use super::apkg::{guid, model_fields};
+use super::errors::Result;
+use super::template::NoteTemplate;
+
+/// Tab separated notes for *File > Import* of Anki, no plugin needed.
+///
+/// The header tells Anki the note type and which columns keep the GUID, the deck and the tags,
+/// the rest of the columns are the fields of the template. GUIDs are the ones of the `.apkg` export,
+/// so importing the file again updates the notes.
+/// Text files carry no media: pictures are referenced by their urls and sounds are left out.
+pub struct TextExport {
+    model_name: String,
+    fields: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl TextExport {
+    pub fn new(template: &NoteTemplate) -> Self {
+        Self {
+            model_name: template.model_name.clone(),
+            fields: model_fields(template),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, meaning_id: u64, note: Note) {
+        let mut fields = note.fields;
+        for picture in note.picture.iter() {
+            for field in picture.fields.iter() {
+                fields.entry(field.clone())
+                    .or_default()
+                    .push_str(&format!("<img src=\"{}\">", picture.url));
+            }
+        }
+        let mut row = vec![guid(meaning_id), note.deck_name];
+        row.extend(self.fields.iter().map(|name| fields.get(name).cloned().unwrap_or_default()));
+        row.push(note.tags.join(" "));
+        self.rows.push(row);
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// File content: header directives and a line per note
+    pub fn to_text(&self) -> String {
+        self.header() + &self.lines()
+    }
+
+    fn header(&self) -> String {
+        let mut columns = vec!["GUID".to_string(), "Deck".to_string()];
+        columns.extend(self.fields.iter().cloned());
+        columns.push("Tags".to_string());
+        format!(
+            "#separator:tab\n#html:true\n#notetype:{}\n#guid column:1\n#deck column:2\n#tags column:{}\n#columns:{}\n",
+            self.model_name,
+            columns.len(),
+            columns.join("\t"),
+        )
+    }
+
+    fn lines(&self) -> String {
+        let mut text = String::new();
+        for row in self.rows.iter() {
+            text.push_str(&row.iter().map(|v| quote(v)).collect::<Vec<_>>().join("\t"));
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Writes the file, replacing it
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_text())?;
+        Ok(())
+    }
+
+    /// Appends the notes to the file, only a new file gets the header.
+    /// Importing the whole file again updates the notes appended before.
+    pub fn append(&self, path: &Path) -> Result<()> {
+        let new = fs::metadata(path).map(|m| m.len() == 0).unwrap_or(true);
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if new {
+            file.write_all(self.header().as_bytes())?;
+        }
+        file.write_all(self.lines().as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Values with a tab, a line break or a quote are quoted, quotes inside are doubled
+fn quote(value: &str) -> String {
+    if value.contains(['\t', '\n', '\r', '"']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lib::notes::test::sample_word;
+    use crate::lib::notes::{AnkiPersistence, NoteSettings};
+    use crate::lib::skyeng::test::sample_meaning;
+    use crate::lib::template::DeckOptions;
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("deter"), "deter");
+        assert_eq!(quote("a\tb"), "\"a\tb\"");
+        assert_eq!(quote("<img src=\"a.png\">"), "\"<img src=\"\"a.png\"\">\"");
+    }
+
+    #[test]
+    fn test_text_export() {
+        let settings = NoteSettings {
+            deck: DeckOptions { deck: "Skyeng".to_string(), subdecks: true, wordset_tags: true },
+            ..NoteSettings::default()
+        };
+        let meaning = sample_meaning();
+        let mut export = TextExport::new(&settings.template);
+        export.add(meaning.id, meaning.to_notes(&sample_word(), &settings));
+        assert_eq!(export.len(), 1);
+
+        let text = export.to_text();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[..7], [
+            "#separator:tab",
+            "#html:true",
+            "#notetype:Cloze",
+            "#guid column:1",
+            "#deck column:2",
+            "#tags column:5",
+            "#columns:GUID\tDeck\tText\tExtra\tTags",
+        ]);
+        let columns = lines[7].split('\t').collect::<Vec<_>>();
+        assert_eq!(columns.len(), 5);
+        assert_eq!(columns[0], guid(210809));
+        assert_eq!(columns[1], "Skyeng::Communication::Informal communication");
+        assert!(columns[2].contains("{{c1::deter"), "{}", columns[2]);
+        assert!(columns[3].contains("5a677c4b4a356e7a4a3fc243deb73676.png"), "{}", columns[3]);
+        assert_eq!(columns[4], "skyeng Communication Informal_communication");
+    }
+}
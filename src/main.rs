@@ -11,238 +11,156 @@ extern crate diesel_migrations;
 // tested without any outside setup of the database.
 embed_migrations!();
 
-use std::env;
+use std::path::PathBuf;
 use std::process::exit;
-use chrono::Utc;
+use chrono::NaiveDateTime;
+use clap::{Args, Parser, Subcommand};
+use log::{error, info};
 use lib::{
     skyeng::*,
-    anki::*,
+    accounts::SkyengClients,
+    commands::{self, ExportFormat},
+    config::Config,
+    daemon,
+    sync::{sync_accounts, DryRunFormat, SyncOptions},
 };
 use crate::lib::db_config::DbConfig;
-use crate::lib::repository::{get_last_update, get_token, save_last_update, save_token, save_word, Word};
-use diesel_migrations::RunMigrationsError;
-
-#[tokio::main]
-async fn main() {
-    env_logger::init();
-    dotenv::dotenv().ok();
-
-    let pool = DbConfig::get_pool();
-    DbConfig::test_connection(pool.clone()).unwrap();
-    let migrations: Result<(),RunMigrationsError> = embedded_migrations::run(&pool.clone().get().unwrap());
-    match migrations {
-        Ok(_) => {},
-        Err(err) => {
-            println!("Unable to run migrations: {}!", err);
-            exit(1);
-        }
-    }
-
-
-    let user = env::var("SKYENG_USERNAME")
-        .expect("SKYENG_USERNAME must be set");
-    let token: Option<Token> = get_token(&pool, &user)
-        .expect("Failed to get last token from db");
-    let last_update = get_last_update(&pool)
-        .expect("Failed to get last update from db");
-
-    let mut skyeng = Skyeng::new_with_token(
-        token,
-        user.clone(),
-        env::var("SKYENG_PASSWORD")
-            .expect("SKYENG_PASSWORD must be set"),
-    );
-
-    let callback_pool = pool.clone();
-    let login = user.clone();
-    skyeng.on_token_update(move |token| {
-        println!("Token updated: {:?}", token);
-        save_token(&callback_pool, &login, token)
-            .expect("Failed to save token to db");
-    });
-
-    let anki = Anki::new(env::var("ANKI_URL").expect("ANKI_URL must be set"));
-    let deck = env::var("ANKI_DECK").unwrap_or("Default".to_string());
-
-    let student = env::var("SKYENG_STUDENT")
-        .expect("SKYENG_STUDENT must be set. E.g.: 123")
-        .parse::<u32>()
-        .expect("SKYENG_STUDENT must be a number");
-
-    let words = skyeng
-        .get_words(&student)
-        .await
-        .expect("Failed to get words from skyeng")
-        .created_after(&last_update);
-    let data = words.iter().map(|w| w.word.clone()).collect::<Vec<_>>();
-    let meanings = skyeng
-        .get_meanings(&data)
-        .await
-        .expect("Failed to get meanings from skyeng");
-
-    // save meanings into anki
-    for note in meanings.iter().map(|m| {
-        m.to_notes(&deck)
-    }) {
-        anki.add_note(note)
-            .await
-            .expect("Failed to add note to anki");
-    }
-    anki.sync()
-        .await
-        .expect("Failed to sync anki");
-
-    // EVENTUALLY
-    // store words in DB
-    to_word_meanings(&student, &words, &meanings).iter().for_each(|w| {
-        save_word(&pool, &w.to_db_word())
-            .expect("Failed to save word to db");
-    });
-
-    //save last added word timestamp if any to DB
-    match words.last_created() {
-        Some(w) => save_last_update(&pool, &w).unwrap(),
-        _ => {}
-    };
+use crate::lib::errors::Result;
+
+/// Exports new words of Skyeng students to Anki
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    /// TOML or YAML config file, environment variables override its values
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// arguments of `sync` when the command is omitted
+    #[command(flatten)]
+    sync: SyncArgs,
 }
 
-pub struct WordMeaning{
-    student_id: u32,
-    word: WordOfSet,
-    meaning: Meaning,
+#[derive(Args)]
+struct SyncArgs {
+    /// builds the notes and prints them instead of sending them to Anki, nothing is stored
+    #[arg(long)]
+    dry_run: bool,
+    /// output of the dry run
+    #[arg(long, value_enum, default_value_t, requires = "dry_run")]
+    format: DryRunFormat,
 }
 
-impl WordMeaning {
-    pub fn to_db_word(&self) -> Word {
-        Word {
-            student_id: self.student_id.into(),
-            wordset_id: self.word.wordset.id.into(),
-            word_id: self.word.word.meaning_id as i64,
-            title: self.word.wordset.title.clone(),
-            subtitle: self.word.wordset.subtitle.clone(),
-            meaning: self.meaning.text.clone(),
-            created_at: self.word.word.created_at.clone(),
-            exported_at: Some(Utc::now().naive_utc()),
-        }
-    }
+#[derive(Subcommand)]
+enum Command {
+    /// Exports new words to Anki, the default command
+    Sync(SyncArgs),
+    /// Checks credentials of the accounts and stores their tokens
+    Login,
+    /// Lists wordsets of the students
+    Wordsets {
+        /// one of the configured students, all of them by default
+        #[arg(long)]
+        student: Option<u32>,
+    },
+    /// Lists words of the students
+    Words {
+        /// one of the configured students, all of them by default
+        #[arg(long)]
+        student: Option<u32>,
+        /// only words created after the time, e.g. 2022-02-10T10:40:57
+        #[arg(long)]
+        after: Option<NaiveDateTime>,
+    },
+    /// Rewinds the sync cursor, so the next sync looks at the older words again
+    ResetCursor {
+        /// one of the configured students, all of them by default
+        #[arg(long)]
+        student: Option<u32>,
+        /// one of the student targets, e.g. `Default` or `Profile/Deck`, all of them by default
+        #[arg(long)]
+        target: Option<String>,
+        /// time to rewind to, e.g. 2022-02-10T10:40:57, the very beginning by default
+        #[arg(long)]
+        to: Option<NaiveDateTime>,
+    },
+    /// Shows tokens, cursors and counts of the stored words
+    Status,
+    /// Runs the sync on the schedule of the config until SIGTERM
+    Daemon,
+    /// Writes the notes into a file to be imported into Anki instead of sending them to AnkiConnect
+    Export {
+        /// file to write, e.g. skyeng.apkg or skyeng.txt
+        #[arg(long)]
+        output: PathBuf,
+        /// what kind of file to write
+        #[arg(long, value_enum, default_value_t)]
+        format: ExportFormat,
+        /// one of the configured students, all of them by default
+        #[arg(long)]
+        student: Option<u32>,
+        /// only words created after the time, e.g. 2022-02-10T10:40:57
+        #[arg(long)]
+        after: Option<NaiveDateTime>,
+    },
 }
 
-pub fn to_word_meanings(student_id: &u32, words: &Vec<WordOfSet>, meanings: &Vec<Meaning>) -> Vec<WordMeaning> {
-    let mut result = Vec::new();
-    for word in words {
-        for meaning in meanings {
-            if &meaning.id == &word.word.meaning_id {
-                result.push(WordMeaning {
-                    student_id: student_id.clone(),
-                    word: word.clone(),
-                    meaning: meaning.clone(),
-                });
-            }
-        }
-    }
-    result
-}
-
-trait ToMaskedText {
-    fn to_masked_text(&self) -> String;
-}
-
-impl ToMaskedText for Meaning {
-    fn to_masked_text(&self) -> String {
-        format!("{} {}", self.text, self.translation.text)
-    }
-}
+/// Exit code of a run, which finished, but failed to export some of the notes
+const EXIT_NOTES_FAILED: i32 = 7;
 
-trait ToAttachment {
-    fn to_attachment(&self, fields: Vec<String>) -> Attachment;
-}
-
-impl ToAttachment for String {
-    fn to_attachment(&self, fields: Vec<String>) -> Attachment  {
-        let filename = if self.contains("?") {
-            format!("{}.mp3",self.split("=").last().unwrap())
-        } else {
-            self.split("/").last().unwrap().to_string()
-        };
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    dotenv::dotenv().ok();
 
-        Attachment {
-            url: self.clone(),
-            filename,
-            fields
+    match run().await {
+        Ok(0) => {}
+        Ok(code) => exit(code),
+        Err(e) => {
+            error!("Failed: {}", e);
+            exit(e.exit_code());
         }
     }
 }
 
-trait AnkiPersistence {
-    fn to_notes(&self, deck_name: &String) -> Note;
-}
-
-impl AnkiPersistence for Meaning {
-    fn to_notes(&self, deck_name: &String) -> Note {
-        let masked_text = self.to_masked_text();
-        let audio = self.sound_url.to_attachment(vec![
-            "Extra".to_string()
-        ]);
-
-        Note {
-            deck_name: deck_name.clone(),
-            model_name: "Cloze".to_string(),
-            fields: Fields {
-                text: masked_text
-            },
-            options: Options {
-                allow_duplicate: false,
-                duplicate_scope: "deck".to_string(),
-                duplicate_scope_options: Some(DuplicateScopeOptions {
-                    deck_name: "Default".to_string(),
-                    check_children: false,
-                    check_all_models: false
-                })
-            },
-            tags: vec![
-                "skyeng".to_string(),
-            ],
-            audio: vec![audio],
-            picture: vec![
-                Attachment {
-                    url: "".to_string(),
-                    filename: "".to_string(),
-                    fields: vec![
-                        "Extra".to_string(),
-                    ]
-                }
-            ]
+/// Runs the command and returns the exit code
+async fn run() -> Result<i32> {
+    let cli = Cli::parse();
+    let config = Config::load(cli.config.as_deref())?;
+
+    let pool = DbConfig::get_pool(&config.database)?;
+    DbConfig::test_connection(pool.clone())?;
+    embedded_migrations::run(&pool.get()?)?;
+
+    match cli.command.unwrap_or(Command::Sync(cli.sync)) {
+        Command::Sync(args) => {
+            let options = SyncOptions {
+                resync: config.anki.resync,
+                dry_run: Some(args.format).filter(|_| args.dry_run),
+                ..SyncOptions::default()
+            };
+            let report = sync_accounts(
+                &pool,
+                &config.skyeng.accounts,
+                &mut SkyengClients::new(config.skyeng.urls.clone()),
+                &config.note_settings(),
+                config.anki.media_cache.as_deref(),
+                &options,
+            ).await?;
+            info!("Sync finished. {}", report);
+            if report.failed > 0 {
+                return Ok(EXIT_NOTES_FAILED);
+            }
         }
+        Command::Login => commands::login(&pool, &config).await?,
+        Command::Wordsets { student } => commands::wordsets(&pool, &config, student).await?,
+        Command::Words { student, after } => commands::words(&pool, &config, student, after).await?,
+        Command::ResetCursor { student, target, to } => {
+            commands::reset_cursor(&pool, &config, student, target.as_deref(), to)?
+        }
+        Command::Status => commands::status(&pool, &config)?,
+        Command::Daemon => daemon::run(&pool, &config).await?,
+        Command::Export { output, format, student, after } => commands::export(&pool, &config, student, after, format, &output).await?,
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    fn test_url_picture_parse() {
-        let url = "https://cdn-user77752.skyeng.ru/resized-images/200x150/png/50/5a677c4b4a356e7a4a3fc243deb73676.png".to_string();
-        let attachment = url.to_attachment(vec![
-            "Extra".to_string()
-        ]);
-        assert_eq!(attachment.url, url);
-        assert_eq!(attachment.filename, "5a677c4b4a356e7a4a3fc243deb73676.png".to_string());
-        assert_eq!(attachment.fields, vec![
-            "Extra".to_string()
-        ]);
-    }
-
-    #[test]
-    fn test_url_sound_parse() {
-        let url = "https://d2fmfepycn0xw0.cloudfront.net?gender=female&accent=american&text=deter".to_string();
-        let attachment = url.to_attachment(vec![
-            "Extra".to_string()
-        ]);
-        assert_eq!(attachment.url, url);
-        assert_eq!(attachment.filename, "deter.mp3".to_string());
-        assert_eq!(attachment.fields, vec![
-            "Extra".to_string()
-        ]);
-    }
+    Ok(0)
 }
@@ -1,5 +1,5 @@
 table! {
-    words (student_id, wordset_id, word_id) {
+    words (student_id, target, wordset_id, word_id) {
         student_id -> Int8,
         wordset_id -> Int8,
         word_id -> Int8,
@@ -8,12 +8,17 @@ table! {
         meaning -> Text,
         created_at -> Text,
         exported_at -> Nullable<Timestamp>,
+        anki_note_id -> Nullable<Int8>,
+        target -> Text,
     }
 }
 
 table! {
-    execution (last_update) {
-        last_update -> Text,
+    sync_cursor (login, student_id, target) {
+        login -> Text,
+        student_id -> Int8,
+        target -> Text,
+        last_update -> Timestamp,
     }
 }
 
@@ -24,3 +29,14 @@ table! {
         expires_at -> Timestamp,
     }
 }
+
+table! {
+    sync_run (finished_at) {
+        finished_at -> Timestamp,
+        added -> Int8,
+        updated -> Int8,
+        skipped -> Int8,
+        failed -> Int8,
+        error -> Nullable<Text>,
+    }
+}